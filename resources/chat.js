@@ -1,59 +1,150 @@
 const chat_window = document.querySelector('#chat-window');
 
-async function get_new_message() {
-    try {
-        const response = await fetch(`${window.location.origin}/new-message`);
-        const message_json = response.json()
-            .then((value) => {
-                if (!document.cookie.includes(value.username)) {
-                    let new_p = document.createElement('p');
-            
-                    let new_node;
-            
-                    new_node = document.createTextNode(`${value.username}: ${value.message}`);
-            
-                    new_p.setAttribute("class", "message-bubble");
-            
-                    new_p.appendChild(new_node);
-                    chat_window.appendChild(new_p);
-            
-                    new_p.scrollIntoView();
-                }
-            });
-
-        new Promise(resolve => setTimeout(resolve, 50));
-
-        get_new_message();
-    } catch (error) {
-        console.error("Error encountered: ", error);
+const load_older = document.querySelector('#load-older');
+
+// Every room's endpoints live under the path of its page
+const room_url = `${window.location.origin}${window.location.pathname.replace(/\/$/, "")}`;
+
+function create_message(text, class_name) {
+    let new_p = document.createElement('p');
+
+    let new_node;
+
+    new_node = document.createTextNode(text);
+
+    new_p.setAttribute("class", class_name);
+
+    new_p.appendChild(new_node);
+
+    return new_p;
+}
+
+function show_message(text, class_name) {
+    const new_p = create_message(text, class_name);
+
+    chat_window.appendChild(new_p);
+
+    new_p.scrollIntoView();
+}
+
+// The session cookie cannot be read from here, so the server says who we are
+const own_username = chat_window.dataset.username;
+
+function is_own_message(value) {
+    return value.username === own_username;
+}
+
+function history_text(value) {
+    return is_own_message(value) ? `You: ${value.message}` : `${value.username}: ${value.message}`;
+}
+
+// Id of the oldest message shown, for paging back through history, and of
+// the newest one, so live messages already loaded as history are skipped
+let oldest_message_id = chat_window.dataset.oldest;
+let newest_message_id = Number(chat_window.dataset.newest);
+
+async function fetch_history(before) {
+    const query = before === undefined ? "" : `?before=${before}`;
+    const history = await (await fetch(`${room_url}/history${query}`)).json();
+
+    if (history.messages.length > 0) {
+        oldest_message_id = history.messages[0].id;
+        newest_message_id = Math.max(newest_message_id, history.messages.at(-1).id);
+    }
+
+    load_older.hidden = !history.more;
+
+    return history;
+}
+
+load_older.addEventListener('click', async () => {
+    const history = await fetch_history(oldest_message_id);
+
+    // Keep the view on the message that was at the top
+    const first = chat_window.firstChild;
+
+    for (const value of history.messages) {
+        chat_window.insertBefore(create_message(history_text(value), "message-bubble"), first);
     }
+});
+
+const members_list = document.querySelector('#members');
+
+async function refresh_members() {
+    const { members } = await (await fetch(`${room_url}/members`)).json();
+
+    members_list.replaceChildren(...members.map(member => {
+        const item = document.createElement('li');
+
+        item.setAttribute("class", member.status);
+        item.textContent = member.username;
+        item.title = member.status;
+
+        return item;
+    }));
 }
 
-get_new_message();
+// Members go away without anything being sent, so check in every so often
+setInterval(refresh_members, 30000);
 
+const event_handlers = {
+    message: value => {
+        if (value.id > newest_message_id && !is_own_message(value)) {
+            show_message(`${value.username}: ${value.message}`, "message-bubble");
+        }
+    },
+    join: value => {
+        show_message(`${value.username} joined the chat`, "system-message");
+        refresh_members();
+    },
+    leave: value => {
+        show_message(`${value.username} left the chat`, "system-message");
+        refresh_members();
+    },
+    system: value => show_message(value.message, "system-message"),
+};
 
-const inputArea = document.querySelector('#user-message');
+let socket;
+let last_event_id;
 
-document.querySelector('#input-area button').addEventListener('click', e => {
-    e.preventDefault();
+function connect() {
+    const cursor = last_event_id === undefined ? "" : `?cursor=${last_event_id}`;
+
+    socket = new WebSocket(`${room_url.replace(/^http/, "ws")}/ws${cursor}`);
 
-    fetch(`${window.location.origin}/message`, {
-        method: "post",
-        body: inputArea.textContent,
+    socket.addEventListener('message', e => {
+        const value = JSON.parse(e.data);
+
+        last_event_id = value.id;
+
+        event_handlers[value.event]?.(value.data);
     });
 
-    let new_p = document.createElement('p');
+    // Pick up where we left off once the server is reachable again
+    socket.addEventListener('close', () => setTimeout(connect, 1000));
+}
 
-    let new_node;
+// The page comes with the latest history, so live events pick up after it
+last_event_id = Number(chat_window.dataset.cursor);
+chat_window.lastElementChild?.scrollIntoView();
 
-    new_node = document.createTextNode(`You: ${inputArea.textContent}`);
+connect();
 
-    new_p.setAttribute("class", "message-bubble");
+const inputArea = document.querySelector('#user-message');
 
-    new_p.appendChild(new_node);
-    chat_window.appendChild(new_p);
+document.querySelector('#input-area button').addEventListener('click', e => {
+    e.preventDefault();
 
-    new_p.scrollIntoView();
+    if (socket.readyState === WebSocket.OPEN) {
+        socket.send(inputArea.textContent);
+    } else {
+        fetch(`${room_url}/message`, {
+            method: "post",
+            body: inputArea.textContent,
+        });
+    }
+
+    show_message(`You: ${inputArea.textContent}`, "message-bubble");
 
     inputArea.textContent = "";
 });
\ No newline at end of file
@@ -0,0 +1,7 @@
+// Scanning the QR code can bring the security code along in the URL
+const pin = new URLSearchParams(window.location.search).get("pin");
+const pin_input = document.querySelector('#pin');
+
+if (pin !== null && pin_input !== null) {
+    pin_input.value = pin;
+}
@@ -0,0 +1,50 @@
+const room_list = document.querySelector('#room-list');
+const create_form = document.querySelector('#create-room');
+const create_error = document.querySelector('#create-error');
+
+// How often the member counts are refreshed
+const REFRESH_INTERVAL = 3000;
+
+function show_rooms(rooms) {
+    room_list.replaceChildren(...rooms.map(room => {
+        let item = document.createElement('li');
+        let link = document.createElement('a');
+
+        link.href = room.path;
+        link.textContent = room.name;
+
+        item.appendChild(link);
+        item.appendChild(document.createTextNode(
+            ` (${room.members} ${room.members === 1 ? "member" : "members"} online)`
+        ));
+
+        return item;
+    }));
+}
+
+async function refresh_rooms() {
+    try {
+        const response = await fetch(`${window.location.origin}/rooms`);
+
+        show_rooms((await response.json()).rooms);
+    } finally {
+        setTimeout(refresh_rooms, REFRESH_INTERVAL);
+    }
+}
+
+create_form.addEventListener('submit', async e => {
+    e.preventDefault();
+
+    const response = await fetch(`${window.location.origin}/rooms`, {
+        method: "post",
+        body: new URLSearchParams(new FormData(create_form)),
+    });
+
+    if (response.ok) {
+        window.location.href = (await response.json()).path;
+    } else {
+        create_error.textContent = await response.text();
+    }
+});
+
+refresh_rooms();
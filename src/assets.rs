@@ -0,0 +1,59 @@
+/// Pairs each file's name with its content, read from `resources/` when the
+/// crate is compiled.
+macro_rules! embed {
+    ($($name:literal),* $(,)?) => {
+        &[$(($name, include_bytes!(concat!(env!("CARGO_MANIFEST_DIR"), "/resources/", $name)))),*]
+    };
+}
+
+/// The pages, scripts and styles of the chat, compiled into the binary so it
+/// can run without a copy of `resources/` next to it.
+pub const EMBEDDED: &[(&str, &[u8])] = embed![
+    "404.html",
+    "base.css",
+    "chat.css",
+    "chat.html",
+    "chat.js",
+    "head.html",
+    "index.html",
+    "index.js",
+    "lobby.css",
+    "lobby.html",
+    "lobby.js",
+];
+
+/// Returns the embedded file with the name, e.g. `chat.js`.
+pub fn get(name: &str) -> Option<&'static [u8]> {
+    EMBEDDED
+        .iter()
+        .find(|(embedded, _)| *embedded == name)
+        .map(|(_, content)| *content)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use std::path::Path;
+
+    #[test]
+    fn test_every_resource_is_embedded() {
+        let resources = Path::new(env!("CARGO_MANIFEST_DIR")).join("resources");
+
+        for entry in fs::read_dir(resources).unwrap() {
+            let path = entry.unwrap().path();
+
+            if !path.is_file() {
+                continue;
+            }
+
+            let name = path.file_name().unwrap().to_str().unwrap();
+
+            assert_eq!(
+                Some(fs::read(&path).unwrap().as_slice()),
+                get(name),
+                "{name} is not embedded"
+            );
+        }
+    }
+}
@@ -0,0 +1,829 @@
+use std::collections::{HashMap, VecDeque};
+use std::io;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Condvar, Mutex};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use crate::error::JsonError;
+use crate::json::Json;
+use crate::store::MessageStore;
+
+/// A message posted to a room. The id, room and timestamp are filled in when
+/// it is published.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct UserMessage {
+    pub id: u64,
+    pub room: String,
+    /// Milliseconds since the Unix epoch.
+    pub timestamp: u64,
+    pub username: String,
+    pub message: String,
+}
+
+impl UserMessage {
+    pub fn new(username: String, message: String) -> Self {
+        Self {
+            id: 0,
+            room: String::new(),
+            timestamp: 0,
+            username,
+            message,
+        }
+    }
+
+    pub fn to_json(&self) -> Json {
+        Json::object([
+            ("id", self.id.into()),
+            ("room", self.room.as_str().into()),
+            ("timestamp", self.timestamp.into()),
+            ("username", self.username.as_str().into()),
+            ("message", self.message.as_str().into()),
+        ])
+    }
+
+    pub fn from_json(json: &Json) -> Result<Self, JsonError> {
+        let string = |field| {
+            json.get(field)
+                .and_then(Json::as_str)
+                .map(str::to_string)
+                .ok_or(JsonError::MissingField(field))
+        };
+
+        let number = |field| {
+            json.get(field)
+                .and_then(Json::as_u64)
+                .ok_or(JsonError::MissingField(field))
+        };
+
+        Ok(Self {
+            id: number("id")?,
+            room: string("room")?,
+            timestamp: number("timestamp")?,
+            username: string("username")?,
+            message: string("message")?,
+        })
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ChatEvent {
+    Message(UserMessage),
+    Join(String),
+    Leave(String),
+    /// A notice from the server itself.
+    System(String),
+}
+
+impl ChatEvent {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::Message(_) => "message",
+            Self::Join(_) => "join",
+            Self::Leave(_) => "leave",
+            Self::System(_) => "system",
+        }
+    }
+
+    pub fn to_json(&self) -> Json {
+        match self {
+            Self::Message(message) => message.to_json(),
+            Self::Join(username) | Self::Leave(username) => {
+                Json::object([("username", username.as_str().into())])
+            }
+            Self::System(notice) => Json::object([("message", notice.as_str().into())]),
+        }
+    }
+}
+
+/// How many messages a room remembers unless told otherwise.
+pub const DEFAULT_HISTORY_SIZE: usize = 100;
+
+/// How long a connected member can go without posting before they are away.
+pub const AWAY_AFTER: Duration = Duration::from_secs(5 * 60);
+
+/// How long members stay listed as offline after they were last active.
+pub const FORGET_OFFLINE_AFTER: Duration = Duration::from_secs(60 * 60);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Status {
+    Online,
+    Away,
+    Offline,
+}
+
+impl Status {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::Online => "online",
+            Self::Away => "away",
+            Self::Offline => "offline",
+        }
+    }
+}
+
+/// Someone who has been in a room recently.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Member {
+    pub username: String,
+    pub status: Status,
+    /// Milliseconds since the Unix epoch.
+    pub last_active: u64,
+}
+
+impl Member {
+    pub fn to_json(&self) -> Json {
+        Json::object([
+            ("username", self.username.as_str().into()),
+            ("status", self.status.name().into()),
+            ("last_active", self.last_active.into()),
+        ])
+    }
+}
+
+/// A member's live connections and when they last did something.
+struct Presence {
+    connections: usize,
+    last_active: u64,
+}
+
+fn unix_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_millis() as u64)
+}
+
+fn millis(duration: Duration) -> u64 {
+    duration.as_millis() as u64
+}
+
+/// How many events a room keeps for clients catching up unless told
+/// otherwise.
+pub const DEFAULT_MAX_LOG_EVENTS: usize = 1000;
+
+/// The newest events published to a room, along with the cursor pointing at
+/// the first of them.
+struct EventLog {
+    offset: u64,
+    events: VecDeque<ChatEvent>,
+    max_events: usize,
+}
+
+impl EventLog {
+    /// Returns the cursor pointing past the newest event.
+    fn end(&self) -> u64 {
+        self.offset + self.events.len() as u64
+    }
+
+    /// Appends the event, dropping the oldest one once the log is full.
+    fn push(&mut self, event: ChatEvent) {
+        if self.events.len() == self.max_events {
+            self.events.pop_front();
+            self.offset += 1;
+        }
+
+        self.events.push_back(event);
+    }
+
+    /// Returns the events past the cursor along with the cursor pointing past
+    /// them. Cursors older than the oldest event kept start from that event.
+    fn since(&self, cursor: u64) -> (Vec<ChatEvent>, u64) {
+        let start = (cursor.saturating_sub(self.offset) as usize).min(self.events.len());
+
+        (self.events.range(start..).cloned().collect(), self.end())
+    }
+}
+
+/// Chat state shared between the threads serving connections.
+///
+/// Every published event is appended to a log. Clients keep a cursor into
+/// the log, the number of events they have seen so far, and ask for
+/// everything past it, so nothing is missed while they are between polls.
+/// Only the newest events are kept, clients that fall further behind than
+/// that miss out on the older ones.
+///
+/// The most recent messages are also kept in a ring buffer, which is what
+/// clients page through to show the room's history.
+pub struct ChatState {
+    room: String,
+    log: Mutex<EventLog>,
+    published: Condvar,
+    history: Mutex<VecDeque<UserMessage>>,
+    history_size: usize,
+    store: Mutex<Option<MessageStore>>,
+    /// Messages published but not yet written to the store, in order. They
+    /// are written after the log is let go of, so a slow disk does not hold
+    /// up everyone reading the log.
+    unwritten: Mutex<VecDeque<UserMessage>>,
+    persisted: bool,
+    presence: Mutex<HashMap<String, Presence>>,
+    closed: AtomicBool,
+}
+
+impl ChatState {
+    pub fn new(room: &str) -> Self {
+        Self::with_history_size(room, DEFAULT_HISTORY_SIZE)
+    }
+
+    /// Creates a room that remembers up to `history_size` messages.
+    pub fn with_history_size(room: &str, history_size: usize) -> Self {
+        Self {
+            room: room.to_string(),
+            log: Mutex::new(EventLog {
+                offset: 0,
+                events: VecDeque::new(),
+                max_events: DEFAULT_MAX_LOG_EVENTS,
+            }),
+            published: Condvar::new(),
+            history: Mutex::new(VecDeque::with_capacity(history_size)),
+            history_size,
+            store: Mutex::new(None),
+            unwritten: Mutex::new(VecDeque::new()),
+            persisted: false,
+            presence: Mutex::new(HashMap::new()),
+            closed: AtomicBool::new(false),
+        }
+    }
+
+    /// Keeps up to `max_log_events` of the newest events for clients catching
+    /// up, at least one.
+    pub fn max_log_events(mut self, max_log_events: usize) -> Self {
+        self.log.get_mut().unwrap().max_events = max_log_events.max(1);
+
+        self
+    }
+
+    /// Writes every message published from now on to the store, after putting
+    /// the messages replayed from it back into the history. Message ids carry
+    /// on from the newest one ever written to the store.
+    pub fn persist(&mut self, store: MessageStore, replayed: Vec<UserMessage>) {
+        let history = self.history.get_mut().unwrap();
+        let log = self.log.get_mut().unwrap();
+
+        log.offset = log.offset.max(store.last_id());
+
+        history.extend(replayed);
+        history.drain(..history.len().saturating_sub(self.history_size));
+
+        *self.store.get_mut().unwrap() = Some(store);
+
+        self.persisted = true;
+    }
+
+    /// Writes the messages published so far to the store. Whoever gets to the
+    /// store first writes everyone's messages, so they land in order.
+    fn write_unwritten(&self) {
+        let mut store = self.store.lock().unwrap();
+
+        let Some(store) = store.as_mut() else {
+            return;
+        };
+
+        while let Some(message) = self.unwritten.lock().unwrap().pop_front() {
+            if let Err(e) = store.append(&message) {
+                eprintln!("Failed to write message to {}: {e}", store.path().display());
+            }
+        }
+    }
+
+    /// Flushes messages written to the store to the disk.
+    pub fn sync_store(&self) -> io::Result<()> {
+        self.write_unwritten();
+
+        match self.store.lock().unwrap().as_mut() {
+            Some(store) => store.sync(),
+            None => Ok(()),
+        }
+    }
+
+    /// Tells everyone connected that the room is closing and flushes its
+    /// messages to the disk. Live connections should end once they have
+    /// passed the notice on.
+    pub fn close(&self, notice: &str) -> io::Result<()> {
+        self.closed.store(true, Ordering::SeqCst);
+
+        self.publish(ChatEvent::System(notice.to_string()));
+
+        self.sync_store()
+    }
+
+    pub fn is_closed(&self) -> bool {
+        self.closed.load(Ordering::SeqCst)
+    }
+
+    pub fn room(&self) -> &str {
+        &self.room
+    }
+
+    /// Returns the cursor pointing past the newest event.
+    pub fn cursor(&self) -> u64 {
+        self.log.lock().unwrap().end()
+    }
+
+    /// Appends the event to the log and wakes every client waiting on it,
+    /// returning the cursor pointing past it. Messages are stamped with their
+    /// id, which is that cursor, along with the room and the current time.
+    pub fn publish(&self, mut event: ChatEvent) -> u64 {
+        // Before taking the log, which `connect` publishes to while holding
+        // on to the presence
+        if let ChatEvent::Message(message) = &event {
+            self.touch(&message.username);
+        }
+
+        let mut log = self.log.lock().unwrap();
+
+        if let ChatEvent::Message(message) = &mut event {
+            message.id = log.end() + 1;
+            message.room = self.room.clone();
+            message.timestamp = unix_millis();
+
+            if self.persisted {
+                self.unwritten.lock().unwrap().push_back(message.clone());
+            }
+
+            let mut history = self.history.lock().unwrap();
+
+            if history.len() == self.history_size {
+                history.pop_front();
+            }
+
+            if self.history_size > 0 {
+                history.push_back(message.clone());
+            }
+        }
+
+        log.push(event);
+
+        self.published.notify_all();
+
+        let cursor = log.end();
+
+        drop(log);
+
+        if self.persisted {
+            self.write_unwritten();
+        }
+
+        cursor
+    }
+
+    /// Returns the events past the cursor along with the cursor pointing
+    /// past them.
+    pub fn events_since(&self, cursor: u64) -> (Vec<ChatEvent>, u64) {
+        self.log.lock().unwrap().since(cursor)
+    }
+
+    /// Like `events_since`, but blocks until there is at least one event past
+    /// the cursor or the timeout elapses.
+    pub fn wait_for_events(&self, cursor: u64, timeout: Duration) -> (Vec<ChatEvent>, u64) {
+        let deadline = Instant::now() + timeout;
+
+        let mut log = self.log.lock().unwrap();
+
+        // Cursors handed out before a restart can point past the newest event
+        let cursor = cursor.min(log.end());
+
+        while log.end() <= cursor {
+            let remaining = deadline.saturating_duration_since(Instant::now());
+
+            if remaining.is_zero() {
+                break;
+            }
+
+            log = self.published.wait_timeout(log, remaining).unwrap().0;
+        }
+
+        log.since(cursor)
+    }
+
+    /// Returns up to `limit` of the newest remembered messages older than the
+    /// message with id `before`, oldest first, and whether there are older
+    /// ones left to page through.
+    pub fn history(&self, before: Option<u64>, limit: usize) -> (Vec<UserMessage>, bool) {
+        let history = self.history.lock().unwrap();
+
+        let end = match before {
+            Some(before) => history.partition_point(|m| m.id < before),
+            None => history.len(),
+        };
+
+        let start = end.saturating_sub(limit);
+
+        (history.range(start..end).cloned().collect(), start > 0)
+    }
+
+    /// Returns the users with a live connection to the room and those who
+    /// left recently, ordered by name.
+    pub fn members(&self) -> Vec<Member> {
+        let mut presence = self.presence.lock().unwrap();
+
+        let now = unix_millis();
+
+        presence.retain(|_, p| {
+            p.connections > 0 || now.saturating_sub(p.last_active) < millis(FORGET_OFFLINE_AFTER)
+        });
+
+        let mut members: Vec<Member> = presence
+            .iter()
+            .map(|(username, p)| Member {
+                username: username.clone(),
+                status: match p.connections {
+                    0 => Status::Offline,
+                    _ if now.saturating_sub(p.last_active) >= millis(AWAY_AFTER) => Status::Away,
+                    _ => Status::Online,
+                },
+                last_active: p.last_active,
+            })
+            .collect();
+
+        members.sort_by(|a, b| a.username.cmp(&b.username));
+
+        members
+    }
+
+    /// Returns how many users have a live connection to the room.
+    pub fn connected(&self) -> usize {
+        self.presence
+            .lock()
+            .unwrap()
+            .values()
+            .filter(|p| p.connections > 0)
+            .count()
+    }
+
+    /// Records that the user did something, so they are not shown as away.
+    fn touch(&self, username: &str) {
+        if let Some(p) = self.presence.lock().unwrap().get_mut(username) {
+            p.last_active = unix_millis();
+        }
+    }
+
+    /// Records that the user opened a live connection, announcing that they
+    /// joined if it is their first one.
+    pub fn connect(&self, username: &str) {
+        let mut presence = self.presence.lock().unwrap();
+
+        let p = presence.entry(username.to_string()).or_insert(Presence {
+            connections: 0,
+            last_active: 0,
+        });
+
+        p.connections += 1;
+        p.last_active = unix_millis();
+
+        if p.connections == 1 {
+            self.publish(ChatEvent::Join(username.to_string()));
+        }
+    }
+
+    /// Records that one of the user's live connections closed, announcing
+    /// that they left if it was their last one.
+    pub fn disconnect(&self, username: &str) {
+        let mut presence = self.presence.lock().unwrap();
+
+        if let Some(p) = presence.get_mut(username).filter(|p| p.connections > 0) {
+            p.connections -= 1;
+
+            if p.connections == 0 {
+                self.publish(ChatEvent::Leave(username.to_string()));
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::store::StoreOptions;
+    use crate::test_support::TempDir;
+    use std::sync::Arc;
+    use std::thread;
+
+    fn message(text: &str) -> ChatEvent {
+        ChatEvent::Message(UserMessage::new("asd".to_string(), text.to_string()))
+    }
+
+    fn texts(events: &[ChatEvent]) -> Vec<&str> {
+        events
+            .iter()
+            .filter_map(|event| match event {
+                ChatEvent::Message(message) => Some(message.message.as_str()),
+                _ => None,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_events_since_cursor() {
+        let chat = ChatState::new("Room");
+
+        chat.publish(message("first"));
+
+        let cursor = chat.cursor();
+
+        chat.publish(message("second"));
+        chat.publish(message("third"));
+
+        let (messages, cursor) = chat.events_since(cursor);
+
+        assert_eq!(vec!["second", "third"], texts(&messages));
+        assert_eq!(3, cursor);
+        assert!(chat.events_since(cursor).0.is_empty());
+    }
+
+    #[test]
+    fn test_log_keeps_newest_events() {
+        let chat = ChatState::new("Room").max_log_events(3);
+
+        let stale = chat.cursor();
+
+        for text in ["first", "second", "third", "fourth", "fifth"] {
+            chat.publish(message(text));
+        }
+
+        // Catching up from before the oldest event kept starts at that event
+        let (events, cursor) = chat.events_since(stale);
+
+        assert_eq!(vec!["third", "fourth", "fifth"], texts(&events));
+        assert_eq!(5, cursor);
+
+        let (events, _) = chat.events_since(3);
+
+        assert_eq!(vec!["fourth", "fifth"], texts(&events));
+
+        chat.publish(message("sixth"));
+
+        assert_eq!(vec!["sixth"], texts(&chat.events_since(cursor).0));
+        assert_eq!(
+            vec!["fourth", "fifth", "sixth"],
+            texts(&chat.wait_for_events(stale, Duration::ZERO).0)
+        );
+    }
+
+    #[test]
+    fn test_wait_for_events_wakes_on_publish() {
+        let chat = Arc::new(ChatState::new("Room"));
+        let cursor = chat.cursor();
+
+        let waiter = {
+            let chat = Arc::clone(&chat);
+
+            thread::spawn(move || chat.wait_for_events(cursor, Duration::from_secs(10)))
+        };
+
+        thread::sleep(Duration::from_millis(50));
+        chat.publish(message("hello"));
+
+        let (messages, cursor) = waiter.join().unwrap();
+
+        assert_eq!(vec!["hello"], texts(&messages));
+        assert_eq!(1, cursor);
+    }
+
+    #[test]
+    fn test_wait_for_events_times_out() {
+        let chat = ChatState::new("Room");
+
+        chat.publish(message("seen"));
+
+        let (messages, cursor) = chat.wait_for_events(1, Duration::from_millis(20));
+
+        assert!(messages.is_empty());
+        assert_eq!(1, cursor);
+    }
+
+    #[test]
+    fn test_close_notifies_waiting_clients() {
+        let chat = ChatState::new("Room");
+
+        thread::scope(|scope| {
+            let waiting = scope.spawn(|| chat.wait_for_events(0, Duration::from_secs(10)));
+
+            thread::sleep(Duration::from_millis(20));
+
+            chat.close("Server shutting down").unwrap();
+
+            assert_eq!(
+                vec![ChatEvent::System("Server shutting down".to_string())],
+                waiting.join().unwrap().0
+            );
+        });
+
+        assert!(chat.is_closed());
+    }
+
+    #[test]
+    fn test_connections_announce_join_and_leave() {
+        let chat = ChatState::new("Room");
+
+        chat.connect("asd");
+        chat.connect("asd");
+        chat.disconnect("asd");
+
+        assert_eq!(
+            vec![ChatEvent::Join("asd".to_string())],
+            chat.events_since(0).0
+        );
+
+        assert_eq!(1, chat.connected());
+        assert_eq!(Status::Online, chat.members()[0].status);
+
+        chat.disconnect("asd");
+        chat.disconnect("asd");
+
+        assert_eq!(0, chat.connected());
+        assert_eq!(Status::Offline, chat.members()[0].status);
+
+        assert_eq!(
+            vec![
+                ChatEvent::Join("asd".to_string()),
+                ChatEvent::Leave("asd".to_string())
+            ],
+            chat.events_since(0).0
+        );
+    }
+
+    #[test]
+    fn test_idle_members_go_away() {
+        let chat = ChatState::new("Room");
+
+        let idle_since = |chat: &ChatState, duration: Duration| {
+            chat.presence
+                .lock()
+                .unwrap()
+                .get_mut("asd")
+                .unwrap()
+                .last_active = unix_millis() - millis(duration);
+        };
+
+        chat.connect("asd");
+        idle_since(&chat, AWAY_AFTER);
+
+        assert_eq!(Status::Away, chat.members()[0].status);
+
+        // Posting a message brings them back
+        chat.publish(message("back"));
+
+        assert_eq!(Status::Online, chat.members()[0].status);
+
+        chat.disconnect("asd");
+        idle_since(&chat, FORGET_OFFLINE_AFTER);
+
+        assert!(chat.members().is_empty());
+    }
+
+    #[test]
+    fn test_history_pages_back() {
+        let chat = ChatState::with_history_size("Room", 3);
+
+        for text in ["one", "two", "three", "four", "five"] {
+            chat.publish(message(text));
+        }
+
+        let (newest, more) = chat.history(None, 2);
+
+        assert_eq!(
+            vec!["four", "five"],
+            newest
+                .iter()
+                .map(|m| m.message.as_str())
+                .collect::<Vec<_>>()
+        );
+        assert!(more);
+
+        let (older, more) = chat.history(Some(newest[0].id), 2);
+
+        assert_eq!(
+            vec!["three"],
+            older.iter().map(|m| m.message.as_str()).collect::<Vec<_>>()
+        );
+        assert!(!more);
+
+        assert!(chat.history(Some(older[0].id), 2).0.is_empty());
+    }
+
+    #[test]
+    fn test_history_skips_other_events() {
+        let chat = ChatState::with_history_size("Room", 2);
+
+        chat.connect("asd");
+        chat.publish(message("hello"));
+        chat.disconnect("asd");
+
+        let (history, more) = chat.history(None, 10);
+
+        assert_eq!(1, history.len());
+        assert_eq!(2, history[0].id);
+        assert!(!more);
+
+        assert!(ChatState::with_history_size("Room", 0)
+            .history(None, 10)
+            .0
+            .is_empty());
+    }
+
+    #[test]
+    fn test_persisted_messages_are_replayed() {
+        let dir = TempDir::new("chat");
+
+        let options = StoreOptions::default();
+
+        let mut chat = ChatState::new("Room");
+        chat.persist(
+            MessageStore::open(dir.path(), "Room", options).unwrap().0,
+            Vec::new(),
+        );
+
+        chat.publish(ChatEvent::Join("asd".to_string()));
+        chat.publish(message("before restart"));
+        drop(chat);
+
+        let (store, replayed) = MessageStore::open(dir.path(), "Room", options).unwrap();
+
+        let mut chat = ChatState::new("Room");
+        chat.persist(store, replayed);
+
+        assert_eq!(2, chat.cursor());
+        assert_eq!(3, chat.publish(message("after restart")));
+
+        let (history, _) = chat.history(None, 10);
+
+        assert_eq!(
+            vec![(2, "before restart"), (3, "after restart")],
+            history
+                .iter()
+                .map(|m| (m.id, m.message.as_str()))
+                .collect::<Vec<_>>()
+        );
+
+        // Cursors from before the restart still pick up new events
+        let (events, cursor) = chat.wait_for_events(10, Duration::from_millis(10));
+
+        assert!(events.is_empty());
+        assert_eq!(3, cursor);
+        assert_eq!(vec!["after restart"], texts(&chat.events_since(2).0));
+    }
+
+    #[test]
+    fn test_ids_carry_on_without_history() {
+        let dir = TempDir::new("chat");
+
+        let options = StoreOptions {
+            keep: 0,
+            max_file_size: 1,
+            ..StoreOptions::default()
+        };
+
+        for expected in [1, 2] {
+            let (store, replayed) = MessageStore::open(dir.path(), "Room", options).unwrap();
+
+            let mut chat = ChatState::with_history_size("Room", 0);
+            chat.persist(store, replayed);
+
+            assert_eq!(expected, chat.publish(message("hello")));
+        }
+    }
+
+    #[test]
+    fn test_publish_stamps_messages() {
+        let chat = ChatState::new("Lobby");
+
+        chat.publish(ChatEvent::Join("asd".to_string()));
+        chat.publish(message("hello"));
+
+        let (events, _) = chat.events_since(1);
+
+        let ChatEvent::Message(published) = &events[0] else {
+            panic!("Expected a message, got {:?}", events[0]);
+        };
+
+        assert_eq!(2, published.id);
+        assert_eq!("Lobby", published.room);
+        assert!(published.timestamp > 0);
+    }
+
+    #[test]
+    fn test_message_json_round_trip() {
+        let message = UserMessage {
+            id: 7,
+            room: "Room".to_string(),
+            timestamp: 1_700_000_000_000,
+            username: "asd".to_string(),
+            message: "He said \"hi\"\nthen left \\o/".to_string(),
+        };
+
+        let json = message.to_json().to_string();
+
+        assert_eq!(
+            "{\"id\": 7, \"room\": \"Room\", \"timestamp\": 1700000000000, \"username\": \"asd\", \"message\": \"He said \\\"hi\\\"\\nthen left \\\\o/\"}",
+            json
+        );
+        assert_eq!(
+            Ok(message),
+            UserMessage::from_json(&Json::parse(&json).unwrap())
+        );
+        assert_eq!(
+            Err(JsonError::MissingField("timestamp")),
+            UserMessage::from_json(&Json::parse("{\"id\": 1, \"room\": \"Room\"}").unwrap())
+        );
+    }
+}
@@ -1,4 +1,4 @@
-use std::{error::Error, fmt};
+use std::{error::Error, fmt, io, time::Duration};
 
 #[derive(Debug, Clone)]
 pub struct RouteAlreadyRegistered;
@@ -10,3 +10,325 @@ impl fmt::Display for RouteAlreadyRegistered {
         write!(f, "Route was already registered to other resource")
     }
 }
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ParseError {
+    MissingRequestLine,
+    MalformedRequestLine(String),
+    MalformedHeader(String),
+    MalformedQuery(String),
+    InvalidContentLength(String),
+    IncompleteBody { expected: usize, received: usize },
+    InvalidEncoding,
+}
+
+impl Error for ParseError {}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::MissingRequestLine => write!(f, "Request is missing its request line"),
+            Self::MalformedRequestLine(line) => write!(f, "Malformed request line: {line}"),
+            Self::MalformedHeader(header) => write!(f, "Malformed header: {header}"),
+            Self::MalformedQuery(query) => write!(f, "Malformed query parameter: {query}"),
+            Self::InvalidContentLength(len) => write!(f, "Invalid Content-Length: {len}"),
+            Self::IncompleteBody { expected, received } => write!(
+                f,
+                "Expected a body of {expected} bytes but only received {received}"
+            ),
+            Self::InvalidEncoding => write!(f, "Request is not valid UTF-8"),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum ReadError {
+    Io(io::Error),
+    ConnectionClosed,
+    HeaderTooLarge,
+    BodyTooLarge,
+    Parse(ParseError),
+}
+
+impl Error for ReadError {}
+
+impl fmt::Display for ReadError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Io(e) => write!(f, "Error reading request: {e}"),
+            Self::ConnectionClosed => write!(f, "Connection was closed by the client"),
+            Self::HeaderTooLarge => write!(f, "Request headers exceed the maximum size"),
+            Self::BodyTooLarge => write!(f, "Request body exceeds the maximum size"),
+            Self::Parse(e) => write!(f, "{e}"),
+        }
+    }
+}
+
+impl From<io::Error> for ReadError {
+    fn from(e: io::Error) -> Self {
+        Self::Io(e)
+    }
+}
+
+impl From<ParseError> for ReadError {
+    fn from(e: ParseError) -> Self {
+        Self::Parse(e)
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct PoolBusy;
+
+impl Error for PoolBusy {}
+
+impl fmt::Display for PoolBusy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "All workers are busy")
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum HandshakeError {
+    InvalidMethod,
+    MissingUpgrade,
+    UnsupportedVersion,
+    MissingKey,
+    InvalidKey(String),
+}
+
+impl Error for HandshakeError {}
+
+impl fmt::Display for HandshakeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::InvalidMethod => write!(f, "WebSocket handshakes must use GET"),
+            Self::MissingUpgrade => write!(f, "Request does not ask to upgrade to a WebSocket"),
+            Self::UnsupportedVersion => write!(f, "Only WebSocket version 13 is supported"),
+            Self::MissingKey => write!(f, "Request is missing its Sec-WebSocket-Key"),
+            Self::InvalidKey(key) => write!(f, "Invalid Sec-WebSocket-Key: {key}"),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum WebSocketError {
+    Io(io::Error),
+    ConnectionClosed,
+    Protocol(&'static str),
+    MessageTooLarge,
+    InvalidUtf8,
+}
+
+impl WebSocketError {
+    /// The status code to close the connection with, if it is still open.
+    pub fn close_code(&self) -> Option<u16> {
+        match self {
+            Self::Io(_) | Self::ConnectionClosed => None,
+            Self::Protocol(_) => Some(1002),
+            Self::MessageTooLarge => Some(1009),
+            Self::InvalidUtf8 => Some(1007),
+        }
+    }
+}
+
+impl Error for WebSocketError {}
+
+impl fmt::Display for WebSocketError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Io(e) => write!(f, "Error on WebSocket connection: {e}"),
+            Self::ConnectionClosed => write!(f, "Connection was closed by the client"),
+            Self::Protocol(reason) => write!(f, "{reason}"),
+            Self::MessageTooLarge => write!(f, "Message exceeds the maximum size"),
+            Self::InvalidUtf8 => write!(f, "Text message is not valid UTF-8"),
+        }
+    }
+}
+
+impl From<io::Error> for WebSocketError {
+    fn from(e: io::Error) -> Self {
+        Self::Io(e)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum JsonError {
+    UnexpectedEnd,
+    UnexpectedCharacter(char, usize),
+    InvalidNumber(String),
+    InvalidEscape(usize),
+    TrailingCharacters(usize),
+    TooDeep,
+    MissingField(&'static str),
+}
+
+impl Error for JsonError {}
+
+impl fmt::Display for JsonError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::UnexpectedEnd => write!(f, "JSON ended unexpectedly"),
+            Self::UnexpectedCharacter(c, pos) => {
+                write!(f, "Unexpected character {c:?} in JSON at {pos}")
+            }
+            Self::InvalidNumber(number) => write!(f, "Invalid JSON number: {number}"),
+            Self::InvalidEscape(pos) => write!(f, "Invalid escape sequence in JSON at {pos}"),
+            Self::TrailingCharacters(pos) => write!(f, "Unexpected data after JSON at {pos}"),
+            Self::TooDeep => write!(f, "JSON is nested too deeply"),
+            Self::MissingField(field) => write!(f, "JSON is missing the field {field}"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TemplateError {
+    UnclosedTag(usize),
+    InvalidTag(String),
+    UnexpectedTag(String),
+    UnclosedBlock(String),
+    MissingInclude(String),
+    IncludeTooDeep,
+}
+
+impl Error for TemplateError {}
+
+impl fmt::Display for TemplateError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::UnclosedTag(pos) => write!(f, "Tag opened at {pos} is never closed"),
+            Self::InvalidTag(tag) => write!(f, "Invalid template tag: {{{{{tag}}}}}"),
+            Self::UnexpectedTag(tag) => write!(f, "Unexpected template tag: {{{{{tag}}}}}"),
+            Self::UnclosedBlock(tag) => write!(f, "Block {{{{{tag}}}}} is never closed"),
+            Self::MissingInclude(name) => write!(f, "Included template {name} does not exist"),
+            Self::IncludeTooDeep => write!(f, "Templates include each other too deeply"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct InvalidSyncPolicy(pub String);
+
+impl Error for InvalidSyncPolicy {}
+
+impl fmt::Display for InvalidSyncPolicy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "Invalid sync policy {}, expected always, never or a number of seconds",
+            self.0
+        )
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct InvalidPinSetting(pub String);
+
+impl Error for InvalidPinSetting {}
+
+impl fmt::Display for InvalidPinSetting {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "Invalid join PIN {}, expected off, random or a number of 4 to 12 digits",
+            self.0
+        )
+    }
+}
+
+/// An option from the command line or the env file the server cannot start
+/// with.
+#[derive(Debug)]
+pub enum OptionError {
+    Io(io::Error),
+    Unknown(String),
+    MissingValue(String),
+    InvalidValue {
+        key: String,
+        value: String,
+        expected: &'static str,
+    },
+}
+
+impl OptionError {
+    pub fn invalid(key: &str, value: &str, expected: &'static str) -> Self {
+        Self::InvalidValue {
+            key: key.to_string(),
+            value: value.to_string(),
+            expected,
+        }
+    }
+}
+
+impl Error for OptionError {}
+
+impl fmt::Display for OptionError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Io(e) => write!(f, "Error reading the env file: {e}"),
+            Self::Unknown(key) => write!(f, "Unknown option {key}"),
+            Self::MissingValue(key) => write!(f, "No value given for {key}"),
+            Self::InvalidValue {
+                key,
+                value,
+                expected,
+            } => write!(f, "Invalid {key} {value}, expected {expected}"),
+        }
+    }
+}
+
+impl From<io::Error> for OptionError {
+    fn from(e: io::Error) -> Self {
+        Self::Io(e)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PinError {
+    Incorrect { attempts_left: u32 },
+    LockedOut(Duration),
+}
+
+impl Error for PinError {}
+
+impl fmt::Display for PinError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Incorrect { attempts_left } => write!(
+                f,
+                "Incorrect security code, {attempts_left} attempts left before being locked out"
+            ),
+            Self::LockedOut(remaining) => write!(
+                f,
+                "Too many incorrect security codes, try again in {} seconds",
+                remaining.as_secs().max(1)
+            ),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum RoomError {
+    InvalidName(String),
+    AlreadyExists(String),
+    TooMany(usize),
+    Io(io::Error),
+}
+
+impl Error for RoomError {}
+
+impl fmt::Display for RoomError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::InvalidName(name) => write!(f, "Invalid room name: {name:?}"),
+            Self::AlreadyExists(name) => write!(f, "Room {name} already exists"),
+            Self::TooMany(max) => write!(f, "There can be no more than {max} rooms"),
+            Self::Io(e) => write!(f, "Error opening room: {e}"),
+        }
+    }
+}
+
+impl From<io::Error> for RoomError {
+    fn from(e: io::Error) -> Self {
+        Self::Io(e)
+    }
+}
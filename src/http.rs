@@ -1,15 +1,409 @@
 use std::collections::HashMap;
 use std::fmt::Display;
+use std::io::{self, Read, Write};
+use std::net::SocketAddr;
+
+use crate::error::{ParseError, ReadError};
+
+pub mod compression;
+pub mod static_files;
+pub mod websocket;
+
+/// MIME types by file extension. Text types say they are UTF-8, which is
+/// what every text file served here is expected to be.
+pub const MIME_MAP: &[(&str, &str)] = &[
+    ("html", "text/html; charset=utf-8"),
+    ("htm", "text/html; charset=utf-8"),
+    ("css", "text/css; charset=utf-8"),
+    ("js", "text/javascript; charset=utf-8"),
+    ("mjs", "text/javascript; charset=utf-8"),
+    ("txt", "text/plain; charset=utf-8"),
+    ("md", "text/markdown; charset=utf-8"),
+    ("csv", "text/csv; charset=utf-8"),
+    ("json", "application/json"),
+    ("map", "application/json"),
+    ("webmanifest", "application/manifest+json"),
+    ("xml", "application/xml"),
+    ("wasm", "application/wasm"),
+    ("pdf", "application/pdf"),
+    ("zip", "application/zip"),
+    ("gz", "application/gzip"),
+    ("svg", "image/svg+xml"),
+    ("png", "image/png"),
+    ("jpg", "image/jpeg"),
+    ("jpeg", "image/jpeg"),
+    ("gif", "image/gif"),
+    ("webp", "image/webp"),
+    ("avif", "image/avif"),
+    ("bmp", "image/bmp"),
+    ("ico", "image/x-icon"),
+    ("woff", "font/woff"),
+    ("woff2", "font/woff2"),
+    ("ttf", "font/ttf"),
+    ("otf", "font/otf"),
+    ("mp3", "audio/mpeg"),
+    ("ogg", "audio/ogg"),
+    ("wav", "audio/wav"),
+    ("mp4", "video/mp4"),
+    ("webm", "video/webm"),
+];
+
+/// The MIME type of files that are not known to be anything in particular.
+pub const DEFAULT_MIME_TYPE: &str = "application/octet-stream";
+
+/// Looks up the MIME type of the file by its last extension, so
+/// `app.min.js` is JavaScript.
+pub fn mime_type_for(file_name: &str) -> Option<&'static str> {
+    let name = file_name.rsplit(['/', '\\']).next().unwrap_or(file_name);
+
+    // Dotfiles like `.env` are named, not typed
+    let (stem, extension) = name.rsplit_once('.')?;
+
+    if stem.is_empty() {
+        return None;
+    }
 
-pub const MIME_MAP: &[(&str, &str)] = &[("js", "text/javascript"), ("css", "text/css")];
-
-pub fn get_mime_type(file_name: &str) -> String {
     MIME_MAP
         .iter()
-        .find(|kv| kv.0 == file_name.split(".").skip(1).next().unwrap())
-        .expect(&format!("Could not determine MIME type for {file_name}"))
-        .1
-        .to_string()
+        .find(|(ext, _)| ext.eq_ignore_ascii_case(extension))
+        .map(|(_, mime_type)| *mime_type)
+}
+
+pub fn get_mime_type(file_name: &str) -> &'static str {
+    mime_type_for(file_name).unwrap_or(DEFAULT_MIME_TYPE)
+}
+
+/// Magic numbers at the start of files, and the MIME type they mark.
+const SIGNATURES: &[(&[u8], &str)] = &[
+    (b"\x89PNG\r\n\x1a\n", "image/png"),
+    (b"\xff\xd8\xff", "image/jpeg"),
+    (b"GIF87a", "image/gif"),
+    (b"GIF89a", "image/gif"),
+    (b"\x00\x00\x01\x00", "image/x-icon"),
+    (b"%PDF-", "application/pdf"),
+    (b"PK\x03\x04", "application/zip"),
+    (b"\x1f\x8b", "application/gzip"),
+    (b"\x00asm", "application/wasm"),
+    (b"wOFF", "font/woff"),
+    (b"wOF2", "font/woff2"),
+    (b"OggS", "audio/ogg"),
+    (b"ID3", "audio/mpeg"),
+];
+
+/// Guesses the MIME type of a file from its first few hundred bytes, for
+/// files whose name does not give it away.
+pub fn sniff_mime_type(content: &[u8]) -> &'static str {
+    if let Some((_, mime_type)) = SIGNATURES
+        .iter()
+        .find(|(magic, _)| content.starts_with(magic))
+    {
+        return mime_type;
+    }
+
+    if content.len() >= 12 && content.starts_with(b"RIFF") {
+        match &content[8..12] {
+            b"WEBP" => return "image/webp",
+            b"WAVE" => return "audio/wav",
+            _ => (),
+        }
+    }
+
+    // The content may have been cut off partway through a character
+    let text = match std::str::from_utf8(content) {
+        Ok(text) => text,
+        Err(e) if e.error_len().is_none() => {
+            std::str::from_utf8(&content[..e.valid_up_to()]).unwrap_or_default()
+        }
+        Err(_) => return DEFAULT_MIME_TYPE,
+    };
+
+    if text.chars().any(|c| c.is_control() && !c.is_whitespace()) {
+        return DEFAULT_MIME_TYPE;
+    }
+
+    let start = text.trim_start().to_ascii_lowercase();
+
+    if start.starts_with("<!doctype html") || start.starts_with("<html") {
+        "text/html; charset=utf-8"
+    } else if start.starts_with("<svg") {
+        "image/svg+xml"
+    } else if start.starts_with("<?xml") {
+        "application/xml"
+    } else {
+        "text/plain; charset=utf-8"
+    }
+}
+
+/// Header fields of a request or response. Names are compared
+/// case-insensitively, fields keep the order they were added in, and a name
+/// may carry several values, e.g. multiple `Set-Cookie` headers.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Headers {
+    fields: Vec<(String, String)>,
+}
+
+impl Headers {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Returns the first value of the header.
+    pub fn get(&self, name: &str) -> Option<&str> {
+        self.fields
+            .iter()
+            .find(|(k, _)| k.eq_ignore_ascii_case(name))
+            .map(|(_, v)| v.as_str())
+    }
+
+    pub fn get_all<'a>(&'a self, name: &'a str) -> impl Iterator<Item = &'a str> + 'a {
+        self.fields
+            .iter()
+            .filter(move |(k, _)| k.eq_ignore_ascii_case(name))
+            .map(|(_, v)| v.as_str())
+    }
+
+    pub fn contains(&self, name: &str) -> bool {
+        self.get(name).is_some()
+    }
+
+    /// Adds a value to the header, keeping any values it already has.
+    pub fn append(&mut self, name: &str, value: &str) {
+        self.fields.push((name.to_string(), value.to_string()));
+    }
+
+    /// Sets the header to a single value, replacing any values it already has
+    /// in the position of the first one.
+    pub fn insert(&mut self, name: &str, value: &str) {
+        match self
+            .fields
+            .iter()
+            .position(|(k, _)| k.eq_ignore_ascii_case(name))
+        {
+            Some(i) => {
+                self.fields[i].1 = value.to_string();
+
+                let rest = self.fields.split_off(i + 1);
+
+                self.fields.extend(
+                    rest.into_iter()
+                        .filter(|(k, _)| !k.eq_ignore_ascii_case(name)),
+                );
+            }
+            None => self.append(name, value),
+        }
+    }
+
+    pub fn remove(&mut self, name: &str) {
+        self.fields.retain(|(k, _)| !k.eq_ignore_ascii_case(name));
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&str, &str)> {
+        self.fields.iter().map(|(k, v)| (k.as_str(), v.as_str()))
+    }
+
+    pub fn len(&self) -> usize {
+        self.fields.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.fields.is_empty()
+    }
+}
+
+/// Decodes `%XX` escapes in a URL component. Escapes that are not valid hex
+/// are kept as they are, and invalid UTF-8 is replaced.
+pub fn percent_decode(input: &str) -> String {
+    let bytes = input.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+
+    let mut i = 0;
+
+    while i < bytes.len() {
+        let escaped = match bytes[i] {
+            b'%' => input
+                .get(i + 1..i + 3)
+                // `from_str_radix` would also take a sign, as in `%+1`
+                .filter(|hex| hex.bytes().all(|b| b.is_ascii_hexdigit()))
+                .and_then(|hex| u8::from_str_radix(hex, 16).ok()),
+            _ => None,
+        };
+
+        match escaped {
+            Some(byte) => {
+                decoded.push(byte);
+                i += 3;
+            }
+            None => {
+                decoded.push(bytes[i]);
+                i += 1;
+            }
+        }
+    }
+
+    String::from_utf8_lossy(&decoded).into_owned()
+}
+
+/// Escapes everything but unreserved characters so the result can be used in
+/// a URL component or cookie value.
+pub fn percent_encode(input: &str) -> String {
+    let mut encoded = String::with_capacity(input.len());
+
+    for byte in input.bytes() {
+        match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' => {
+                encoded.push(byte as char)
+            }
+            _ => encoded.push_str(&format!("%{byte:02X}")),
+        }
+    }
+
+    encoded
+}
+
+/// Parses `application/x-www-form-urlencoded` data, as found in query strings
+/// and form bodies, decoding `+` and `%XX` in both keys and values.
+pub fn parse_form(input: &str) -> Result<HashMap<String, String>, ParseError> {
+    let mut form = HashMap::new();
+
+    for pair in input.split('&').filter(|p| !p.is_empty()) {
+        let (key, value) = pair
+            .split_once('=')
+            .ok_or_else(|| ParseError::MalformedQuery(pair.to_string()))?;
+
+        form.insert(
+            percent_decode(&key.replace('+', " ")),
+            percent_decode(&value.replace('+', " ")),
+        );
+    }
+
+    Ok(form)
+}
+
+/// Formats a server-sent event, splitting multi-line data over several `data`
+/// fields. Empty data still gets one, as browsers drop events without any.
+pub fn server_sent_event(id: Option<u64>, event: &str, data: &str) -> String {
+    let mut frame = String::new();
+
+    if let Some(id) = id {
+        frame.push_str(&format!("id: {id}\n"));
+    }
+
+    frame.push_str(&format!("event: {event}\n"));
+
+    for line in data.split('\n') {
+        let line = line.strip_suffix('\r').unwrap_or(line);
+
+        frame.push_str(&format!("data: {line}\n"));
+    }
+
+    frame.push('\n');
+
+    frame
+}
+
+/// Whether browsers send a cookie along with requests made from other sites.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SameSite {
+    Strict,
+    Lax,
+    None,
+}
+
+/// A cookie to be set with a `Set-Cookie` header. The name and value are
+/// percent-encoded, so separators such as `;` or `,`, whitespace and control
+/// characters in them cannot add attributes or other cookies.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Cookie {
+    name: String,
+    value: String,
+    path: Option<String>,
+    max_age: Option<u64>,
+    http_only: bool,
+    secure: bool,
+    same_site: Option<SameSite>,
+}
+
+impl Cookie {
+    pub fn new(name: &str, value: &str) -> Self {
+        Self {
+            name: percent_encode(name),
+            value: percent_encode(value),
+            path: None,
+            max_age: None,
+            http_only: false,
+            secure: false,
+            same_site: None,
+        }
+    }
+
+    /// A cookie that makes the browser forget the one of the same name and
+    /// path.
+    pub fn removal(name: &str) -> Self {
+        Self::new(name, "").max_age(0)
+    }
+
+    pub fn path(mut self, path: &str) -> Self {
+        self.path = Some(path.to_string());
+
+        self
+    }
+
+    /// Seconds until the cookie expires. Without one it lasts until the
+    /// browser is closed.
+    pub fn max_age(mut self, seconds: u64) -> Self {
+        self.max_age = Some(seconds);
+
+        self
+    }
+
+    /// Hides the cookie from scripts running on the page.
+    pub fn http_only(mut self, http_only: bool) -> Self {
+        self.http_only = http_only;
+
+        self
+    }
+
+    /// Only sends the cookie over HTTPS.
+    pub fn secure(mut self, secure: bool) -> Self {
+        self.secure = secure;
+
+        self
+    }
+
+    pub fn same_site(mut self, same_site: SameSite) -> Self {
+        self.same_site = Some(same_site);
+
+        self
+    }
+}
+
+impl Display for Cookie {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}={}", self.name, self.value)?;
+
+        if let Some(path) = self.path.as_ref() {
+            write!(f, "; Path={path}")?;
+        }
+
+        if let Some(max_age) = self.max_age {
+            write!(f, "; Max-Age={max_age}")?;
+        }
+
+        if self.http_only {
+            write!(f, "; HttpOnly")?;
+        }
+
+        if self.secure {
+            write!(f, "; Secure")?;
+        }
+
+        match self.same_site {
+            Some(SameSite::Strict) => write!(f, "; SameSite=Strict"),
+            Some(SameSite::Lax) => write!(f, "; SameSite=Lax"),
+            Some(SameSite::None) => write!(f, "; SameSite=None"),
+            None => Ok(()),
+        }
+    }
 }
 
 #[derive(Debug, PartialEq, Eq)]
@@ -17,86 +411,309 @@ pub struct HttpRequest {
     pub method: String,
     pub resource: String,
     pub http_version: String,
-    headers: HashMap<String, String>,
+    headers: Headers,
     pub body: Option<String>,
+    /// The address of the client that sent the request, when it came over
+    /// a connection.
+    pub peer_addr: Option<SocketAddr>,
     querys: Option<HashMap<String, String>>,
+    params: HashMap<String, String>,
 }
 
 impl HttpRequest {
-    pub fn parse(raw_request: &str) -> Self {
-        let sections = raw_request.split("\r\n").collect::<Vec<&str>>();
+    pub fn parse(raw_request: &str) -> Result<Self, ParseError> {
+        let (head, rest) = raw_request
+            .split_once("\r\n\r\n")
+            .unwrap_or((raw_request, ""));
+
+        let mut request = Self::parse_head(head)?;
+
+        if let Some(len) = request.content_length()? {
+            let body = rest
+                .as_bytes()
+                .get(0..len)
+                .ok_or(ParseError::IncompleteBody {
+                    expected: len,
+                    received: rest.len(),
+                })?;
+
+            // Every byte arrived, but the length ends partway through a
+            // character
+            let body = std::str::from_utf8(body).map_err(|_| ParseError::InvalidEncoding)?;
+
+            request.body = Some(body.to_string());
+        }
 
-        let mut request_line = sections[0].split_whitespace();
+        Ok(request)
+    }
 
-        let mut headers: HashMap<String, String> = HashMap::new();
+    /// Parses the request line and headers of a request, leaving the body
+    /// empty.
+    fn parse_head(head: &str) -> Result<Self, ParseError> {
+        let mut sections = head.split("\r\n");
+
+        let request_line = sections
+            .next()
+            .filter(|l| !l.trim().is_empty())
+            .ok_or(ParseError::MissingRequestLine)?;
+
+        let mut request_line_parts = request_line.split_whitespace();
+
+        let (Some(method), Some(target), Some(http_version), None) = (
+            request_line_parts.next(),
+            request_line_parts.next(),
+            request_line_parts.next(),
+            request_line_parts.next(),
+        ) else {
+            return Err(ParseError::MalformedRequestLine(request_line.to_string()));
+        };
 
-        sections
-            .iter()
-            .skip(1)
-            .take_while(|s| !s.is_empty())
-            .map(|s| s.to_string())
-            .filter(|h| !h.is_empty())
-            .for_each(|h| {
-                let mut pieces = h.split(": ").map(|p| p.to_string());
-
-                headers.insert(
-                    pieces.next().unwrap().to_string(),
-                    pieces.next().unwrap().to_string(),
-                );
-            });
+        let mut headers = Headers::new();
 
-        let body: Option<String>;
+        for h in sections.filter(|h| !h.is_empty()) {
+            let (key, value) = h
+                .split_once(':')
+                .filter(|(k, _)| !k.is_empty() && !k.contains(char::is_whitespace))
+                .ok_or_else(|| ParseError::MalformedHeader(h.to_string()))?;
 
-        match headers.get("Content-Length") {
-            Some(len) => body = Some(sections.last().unwrap()[0..len.parse().unwrap()].to_string()),
-            None => body = None,
+            headers.append(key, value.trim());
         }
 
-        let method = request_line.next().unwrap().to_string();
+        let (resource, querys) = match target.split_once('?') {
+            Some((resource, query_set)) => (resource, Some(parse_form(query_set)?)),
+            None => (target, None),
+        };
 
-        let mut resource_set = request_line.next().unwrap().split("?");
-        let resource = resource_set.next().unwrap().to_string();
+        Ok(Self {
+            method: method.to_string(),
+            resource: resource.to_string(),
+            http_version: http_version.to_string(),
+            headers,
+            body: None,
+            peer_addr: None,
+            querys,
+            params: HashMap::new(),
+        })
+    }
+
+    pub fn content_length(&self) -> Result<Option<usize>, ParseError> {
+        match self.get_header("Content-Length") {
+            Some(len) => len
+                .parse()
+                .map(Some)
+                .map_err(|_| ParseError::InvalidContentLength(len.to_string())),
+            None => Ok(None),
+        }
+    }
 
-        if let Some(query_set) = resource_set.next() {
-            let query_set_iter = query_set.split("&");
+    pub fn get_header(&self, header_name: &str) -> Option<&str> {
+        self.headers.get(header_name)
+    }
 
-            let mut querys = HashMap::new();
+    pub fn headers(&self) -> &Headers {
+        &self.headers
+    }
 
-            query_set_iter.for_each(|q| {
-                let mut key_val = q.split("=");
+    /// Whether the client wants the connection kept open after the response,
+    /// which is the default from HTTP/1.1 on unless it sends
+    /// `Connection: close`.
+    pub fn keep_alive(&self) -> bool {
+        let has_option = |option: &str| {
+            self.headers
+                .get_all("Connection")
+                .flat_map(|c| c.split(','))
+                .any(|c| c.trim().eq_ignore_ascii_case(option))
+        };
 
-                querys.insert(
-                    key_val.next().unwrap().to_string(),
-                    key_val.next().unwrap().to_string(),
-                );
-            });
+        if has_option("close") {
+            false
+        } else if has_option("keep-alive") {
+            true
+        } else {
+            self.http_version != "HTTP/1.0"
+        }
+    }
+
+    /// Returns the decoded value of a parameter from the query string.
+    pub fn query(&self, name: &str) -> Option<&str> {
+        self.querys.as_ref()?.get(name).map(|q| q.as_str())
+    }
+
+    /// Decodes an `application/x-www-form-urlencoded` body. A request without
+    /// a body yields an empty form.
+    pub fn form(&self) -> Result<HashMap<String, String>, ParseError> {
+        match self.body.as_ref() {
+            Some(body) => parse_form(body),
+            None => Ok(HashMap::new()),
+        }
+    }
+
+    /// Returns the value of a cookie the client sent, as it was sent.
+    pub fn cookie(&self, name: &str) -> Option<&str> {
+        self.headers
+            .get_all("Cookie")
+            .flat_map(|h| h.split(';'))
+            .filter_map(|c| c.trim().split_once('='))
+            .find(|(n, _)| *n == name)
+            .map(|(_, value)| value)
+    }
+
+    /// Returns a parameter captured from the resource by the route pattern
+    /// the request was dispatched to.
+    pub fn param(&self, name: &str) -> Option<&str> {
+        self.params.get(name).map(|p| p.as_str())
+    }
+
+    pub(crate) fn set_params(&mut self, params: HashMap<String, String>) {
+        self.params = params;
+    }
+}
 
-            let http_version = request_line.next().unwrap().to_string();
+/// Upper bounds on the size of a request accepted by a `RequestReader`.
+#[derive(Debug, Clone, Copy)]
+pub struct RequestLimits {
+    pub max_header_size: usize,
+    pub max_body_size: usize,
+}
+
+impl Default for RequestLimits {
+    fn default() -> Self {
+        Self {
+            max_header_size: 8 * 1024,
+            max_body_size: 1024 * 1024,
+        }
+    }
+}
+
+/// Reads requests off of a stream, buffering anything read past the end of a
+/// request so it is available to the next call to `read_request`.
+pub struct RequestReader<R> {
+    inner: R,
+    buf: Vec<u8>,
+    limits: RequestLimits,
+}
+
+impl<R: Read> RequestReader<R> {
+    pub fn new(inner: R, limits: RequestLimits) -> Self {
+        Self {
+            inner,
+            buf: Vec::new(),
+            limits,
+        }
+    }
+
+    pub fn get_ref(&self) -> &R {
+        &self.inner
+    }
 
-            Self {
-                method,
-                resource,
-                http_version,
-                headers,
-                body,
-                querys: Some(querys),
+    pub fn get_mut(&mut self) -> &mut R {
+        &mut self.inner
+    }
+
+    /// Reads the headers of the next request up to the blank line, then
+    /// exactly as many bytes of body as its `Content-Length` announces.
+    pub fn read_request(&mut self) -> Result<HttpRequest, ReadError> {
+        let head_end = loop {
+            if let Some(i) = self.buf.windows(4).position(|w| w == b"\r\n\r\n") {
+                break i + 4;
             }
-        } else {
-            let http_version = request_line.next().unwrap().to_string();
-
-            Self {
-                method,
-                resource,
-                http_version,
-                headers,
-                body,
-                querys: None,
+
+            if self.buf.len() > self.limits.max_header_size {
+                return Err(ReadError::HeaderTooLarge);
             }
+
+            if self.fill()? == 0 {
+                return Err(match self.buf.is_empty() {
+                    true => ReadError::ConnectionClosed,
+                    false => ReadError::Io(io::ErrorKind::UnexpectedEof.into()),
+                });
+            }
+        };
+
+        if head_end > self.limits.max_header_size {
+            return Err(ReadError::HeaderTooLarge);
         }
+
+        let head = std::str::from_utf8(&self.buf[..head_end - 4])
+            .map_err(|_| ParseError::InvalidEncoding)?;
+
+        let mut request = HttpRequest::parse_head(head)?;
+
+        let body_len = request.content_length()?;
+
+        if let Some(len) = body_len {
+            if len > self.limits.max_body_size {
+                return Err(ReadError::BodyTooLarge);
+            }
+
+            while self.buf.len() < head_end + len {
+                if self.fill()? == 0 {
+                    return Err(ReadError::Io(io::ErrorKind::UnexpectedEof.into()));
+                }
+            }
+
+            let body = std::str::from_utf8(&self.buf[head_end..head_end + len])
+                .map_err(|_| ParseError::InvalidEncoding)?;
+
+            request.body = Some(body.to_string());
+        }
+
+        self.buf.drain(..head_end + body_len.unwrap_or(0));
+
+        Ok(request)
     }
 
-    pub fn get_header(&self, header_name: &str) -> Option<&String> {
-        self.headers.get(header_name)
+    fn fill(&mut self) -> io::Result<usize> {
+        let mut chunk = [0; 4096];
+
+        let read = self.inner.read(&mut chunk)?;
+
+        self.buf.extend_from_slice(&chunk[..read]);
+
+        Ok(read)
+    }
+}
+
+// Once a connection is upgraded to another protocol, whatever the client sent
+// past the upgrade request belongs to that protocol, so it is read first.
+impl<R: Read> Read for RequestReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.buf.is_empty() {
+            return self.inner.read(buf);
+        }
+
+        let len = buf.len().min(self.buf.len());
+
+        buf[..len].copy_from_slice(&self.buf[..len]);
+        self.buf.drain(..len);
+
+        Ok(len)
+    }
+}
+
+/// Writes a response body that is produced while it is being sent, e.g. a
+/// stream of server-sent events. The connection is closed once it returns.
+pub type StreamFn = Box<dyn FnOnce(&mut dyn Write) -> io::Result<()> + Send>;
+
+struct StreamBody(StreamFn);
+
+impl std::fmt::Debug for StreamBody {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "StreamBody")
+    }
+}
+
+/// Takes over the connection after a `101 Switching Protocols` response has
+/// been sent, e.g. to speak WebSocket. The connection is closed once it
+/// returns.
+pub type UpgradeFn =
+    Box<dyn FnOnce(&mut (dyn Read + Send), &mut (dyn Write + Send)) -> io::Result<()> + Send>;
+
+struct Upgrade(UpgradeFn);
+
+impl std::fmt::Debug for Upgrade {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Upgrade")
     }
 }
 
@@ -105,8 +722,10 @@ pub struct HttpResponse {
     pub http_version: String,
     pub status_code: u32,
     pub status_message: String,
-    headers: HashMap<String, String>,
-    body: Option<String>,
+    headers: Headers,
+    body: Option<Vec<u8>>,
+    stream: Option<StreamBody>,
+    upgrade: Option<Upgrade>,
 }
 
 impl HttpResponse {
@@ -115,43 +734,118 @@ impl HttpResponse {
             http_version,
             status_code,
             status_message,
-            headers: HashMap::new(),
+            headers: Headers::new(),
             body: None,
+            stream: None,
+            upgrade: None,
         }
     }
 
-    pub fn builder() -> HttpResponseBuilder {
-        HttpResponseBuilder::default()
+    pub fn headers(&self) -> &Headers {
+        &self.headers
     }
-}
 
-impl Display for HttpResponse {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        let status_line = format!(
+    pub fn headers_mut(&mut self) -> &mut Headers {
+        &mut self.headers
+    }
+
+    pub fn body(&self) -> Option<&[u8]> {
+        self.body.as_deref()
+    }
+
+    pub fn is_streaming(&self) -> bool {
+        self.stream.is_some()
+    }
+
+    /// Removes the function that takes over the connection once the response
+    /// is sent, if the response upgrades it.
+    pub fn take_upgrade(&mut self) -> Option<UpgradeFn> {
+        self.upgrade.take().map(|Upgrade(upgrade)| upgrade)
+    }
+
+    /// Serializes the status line and headers, including the blank line that
+    /// separates them from the body.
+    fn head(&self) -> String {
+        let mut head = format!(
             "{} {} {}\r\n",
             self.http_version, self.status_code, self.status_message
         );
 
-        let mut headers = String::new();
-
         for (k, v) in self.headers.iter() {
-            headers.push_str(&format!("{}: {}\r\n", k, v));
+            head.push_str(&format!("{}: {}\r\n", k, v));
         }
 
+        head.push_str("\r\n");
+
+        head
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = self.head().into_bytes();
+
+        if let Some(body) = self.body.as_ref() {
+            bytes.extend_from_slice(body);
+        }
+
+        bytes
+    }
+
+    /// Sends the response, running its streaming body to completion if it has
+    /// one.
+    pub fn write_to<W: Write>(self, writer: &mut W) -> io::Result<()> {
+        writer.write_all(&self.to_bytes())?;
+        writer.flush()?;
+
+        if let Some(StreamBody(stream)) = self.stream {
+            stream(writer)?;
+        }
+
+        writer.flush()
+    }
+
+    pub fn builder() -> HttpResponseBuilder {
+        HttpResponseBuilder::default()
+    }
+
+    pub fn bad_request(message: &str) -> Self {
+        Self::builder()
+            .http_version("HTTP/1.1")
+            .status_code(400)
+            .status_message("Bad Request")
+            .add_header("Content-Type", "text/plain")
+            .body(message)
+            .build()
+    }
+
+    pub fn not_found() -> Self {
+        Self::builder()
+            .http_version("HTTP/1.1")
+            .status_code(404)
+            .status_message("Not Found")
+            .build()
+    }
+}
+
+// Bodies that are not UTF-8 are displayed lossily, use `to_bytes` or
+// `write_to` to send a response.
+impl Display for HttpResponse {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self.body.as_ref() {
-            Some(b) => write!(f, "{}{}\r\n{}", status_line, headers, b),
-            None => write!(f, "{}{}\r\n", status_line, headers),
+            Some(b) => write!(f, "{}{}", self.head(), String::from_utf8_lossy(b)),
+            None => write!(f, "{}", self.head()),
         }
     }
 }
 
-#[derive(Default)]
+#[derive(Debug, Default)]
 pub struct HttpResponseBuilder {
     http_version: Option<String>,
     status_code: Option<u32>,
     status_message: Option<String>,
-    headers: HashMap<String, String>,
-    body: Option<String>,
+    headers: Headers,
+    body: Option<Vec<u8>>,
+    stream: Option<StreamBody>,
+    upgrade: Option<Upgrade>,
 }
 
 impl HttpResponseBuilder {
@@ -173,29 +867,87 @@ impl HttpResponseBuilder {
         self
     }
 
+    /// Sets the header, replacing any value it was previously given.
     pub fn add_header(mut self, key: &str, value: &str) -> Self {
-        self.headers.insert(key.to_string(), value.to_string());
+        self.headers.insert(key, value);
+
+        self
+    }
+
+    /// Adds another value for the header alongside any it already has.
+    pub fn append_header(mut self, key: &str, value: &str) -> Self {
+        self.headers.append(key, value);
+
+        self
+    }
+
+    pub fn body(self, body: &str) -> Self {
+        self.bytes(body.as_bytes().to_vec())
+    }
+
+    pub fn bytes(mut self, body: Vec<u8>) -> Self {
+        self.body = Some(body);
 
         self
     }
 
-    pub fn body(mut self, body: &str) -> Self {
-        self.body = Some(body.to_string());
+    /// Gives the response a body that is written by the function once the
+    /// headers are sent, instead of one with a known length.
+    pub fn stream<F>(mut self, stream: F) -> Self
+    where
+        F: FnOnce(&mut dyn Write) -> io::Result<()> + Send + 'static,
+    {
+        self.stream = Some(StreamBody(Box::new(stream)));
+
+        self
+    }
+
+    /// Hands the connection over to the function once the response is sent.
+    pub fn upgrade<F>(mut self, upgrade: F) -> Self
+    where
+        F: FnOnce(&mut (dyn Read + Send), &mut (dyn Write + Send)) -> io::Result<()>
+            + Send
+            + 'static,
+    {
+        self.upgrade = Some(Upgrade(Box::new(upgrade)));
 
         self
     }
 
     pub fn add_cookie(self, cookie: &str) -> Self {
-        self.add_header("Set-Cookie", cookie)
+        self.append_header("Set-Cookie", cookie)
     }
 
-    pub fn build(self) -> HttpResponse {
+    pub fn set_cookie(self, cookie: &Cookie) -> Self {
+        self.add_cookie(&cookie.to_string())
+    }
+
+    /// Builds the response, setting `Content-Length` from the body. Responses
+    /// that cannot have a body or stream theirs are left without one.
+    pub fn build(mut self) -> HttpResponse {
+        let status_code = self.status_code.unwrap_or(404);
+
+        match self.body.as_ref() {
+            _ if self.stream.is_some() => (),
+            Some(body) => self
+                .headers
+                .insert("Content-Length", &format!("{}", body.len())),
+            None if !matches!(status_code, 100..=199 | 204 | 304)
+                && !self.headers.contains("Content-Length") =>
+            {
+                self.headers.insert("Content-Length", "0")
+            }
+            None => (),
+        }
+
         HttpResponse {
             http_version: self.http_version.unwrap_or("HTTP/1.1".to_string()),
-            status_code: self.status_code.unwrap_or(404),
+            status_code,
             status_message: self.status_message.unwrap_or("Not Found".to_string()),
             headers: self.headers,
             body: self.body,
+            stream: self.stream,
+            upgrade: self.upgrade,
         }
     }
 }
@@ -204,6 +956,46 @@ impl HttpResponseBuilder {
 mod tests {
     use super::*;
 
+    #[test]
+    fn test_mime_types() {
+        assert_eq!("text/javascript; charset=utf-8", get_mime_type("chat.js"));
+        assert_eq!(
+            "text/javascript; charset=utf-8",
+            get_mime_type("app.min.js")
+        );
+        assert_eq!("image/svg+xml", get_mime_type("/static/img/logo.SVG"));
+        assert_eq!("font/woff2", get_mime_type("fonts/v1.2/inter.woff2"));
+        assert_eq!("text/html; charset=utf-8", get_mime_type("404.html"));
+
+        assert_eq!(None, mime_type_for("README"));
+        assert_eq!(None, mime_type_for(".env"));
+        assert_eq!(None, mime_type_for("v1.2/LICENSE"));
+        assert_eq!(DEFAULT_MIME_TYPE, get_mime_type("archive.tar.xz"));
+    }
+
+    #[test]
+    fn test_sniff_mime_types() {
+        assert_eq!(
+            "image/png",
+            sniff_mime_type(b"\x89PNG\r\n\x1a\n\0\0\0\rIHDR")
+        );
+        assert_eq!("image/webp", sniff_mime_type(b"RIFF\x24\0\0\0WEBPVP8 "));
+        assert_eq!("application/wasm", sniff_mime_type(b"\0asm\x01\0\0\0"));
+        assert_eq!(
+            "text/html; charset=utf-8",
+            sniff_mime_type(b"\n  <!DOCTYPE HTML><html>")
+        );
+        assert_eq!("image/svg+xml", sniff_mime_type(b"<svg xmlns="));
+        assert_eq!("text/plain; charset=utf-8", sniff_mime_type(b"port=8080\n"));
+
+        // Cut off in the middle of a multi-byte character
+        assert_eq!(
+            "text/plain; charset=utf-8",
+            sniff_mime_type(&"caf\u{e9}".as_bytes()[..4])
+        );
+        assert_eq!(DEFAULT_MIME_TYPE, sniff_mime_type(b"\x01\x02\x03\xff\xfe"));
+    }
+
     #[test]
     fn test_parse_request_no_body() {
         let request = [
@@ -225,26 +1017,22 @@ mod tests {
             request_str.push_str("\r\n");
         }
 
-        let mut headers = HashMap::new();
+        let mut headers = Headers::new();
 
-        headers.insert("Host".to_string(), "192.168.4.28:1234".to_string());
-        headers.insert(
-            "User-Agent".to_string(),
-            "Mozilla/5.0 (X11; Linux x86_64; rv:131.0) Gecko/20100101 Firefox/131.0".to_string(),
-        );
-        headers.insert(
-            "Accept".to_string(),
-            "image/avif,image/webp,image/png,image/svg+xml,image/*;q=0.8,*/*;q=0.5".to_string(),
+        headers.append("Host", "192.168.4.28:1234");
+        headers.append(
+            "User-Agent",
+            "Mozilla/5.0 (X11; Linux x86_64; rv:131.0) Gecko/20100101 Firefox/131.0",
         );
-        headers.insert("Accept-Language".to_string(), "en-US,en;q=0.5".to_string());
-        headers.insert("Accept-Encoding".to_string(), "gzip, deflate".to_string());
-        headers.insert("Connection".to_string(), "keep-alive".to_string());
-        headers.insert("Connection".to_string(), "keep-alive".to_string());
-        headers.insert(
-            "Referer".to_string(),
-            "http://192.168.4.28:1234/".to_string(),
+        headers.append(
+            "Accept",
+            "image/avif,image/webp,image/png,image/svg+xml,image/*;q=0.8,*/*;q=0.5",
         );
-        headers.insert("Priority".to_string(), "u=6".to_string());
+        headers.append("Accept-Language", "en-US,en;q=0.5");
+        headers.append("Accept-Encoding", "gzip, deflate");
+        headers.append("Connection", "keep-alive");
+        headers.append("Referer", "http://192.168.4.28:1234/");
+        headers.append("Priority", "u=6");
 
         request_str.push_str("\r\n");
 
@@ -254,10 +1042,12 @@ mod tests {
             http_version: "HTTP/1.1".to_string(),
             headers,
             body: None,
+            peer_addr: None,
             querys: None,
+            params: HashMap::new(),
         };
 
-        assert_eq!(control, HttpRequest::parse(&request_str));
+        assert_eq!(control, HttpRequest::parse(&request_str).unwrap());
     }
 
     #[test]
@@ -289,29 +1079,23 @@ mod tests {
 
         request_str.push_str("username=asd");
 
-        let mut headers = HashMap::new();
+        let mut headers = Headers::new();
 
-        headers.insert("Host".to_string(), "192.168.4.28:1234".to_string());
-        headers.insert(
-            "User-Agent".to_string(),
-            "Mozilla/5.0 (X11; Linux x86_64; rv:131.0) Gecko/20100101 Firefox/131.0".to_string(),
-        );
-        headers.insert("Accept".to_string(), "text/html,application/xhtml+xml,application/xml;q=0.9,image/avif,image/webp,image/png,image/svg+xml,*/*;q=0.8".to_string());
-        headers.insert("Accept-Language".to_string(), "en-US,en;q=0.5".to_string());
-        headers.insert("Accept-Encoding".to_string(), "gzip, deflate".to_string());
-        headers.insert(
-            "Content-Type".to_string(),
-            "application/x-www-form-urlencoded".to_string(),
-        );
-        headers.insert("Content-Length".to_string(), "12".to_string());
-        headers.insert("Origin".to_string(), "http://192.168.4.28:1234".to_string());
-        headers.insert("Connection".to_string(), "keep-alive".to_string());
-        headers.insert(
-            "Referer".to_string(),
-            "http://192.168.4.28:1234/".to_string(),
+        headers.append("Host", "192.168.4.28:1234");
+        headers.append(
+            "User-Agent",
+            "Mozilla/5.0 (X11; Linux x86_64; rv:131.0) Gecko/20100101 Firefox/131.0",
         );
-        headers.insert("Upgrade-Insecure-Requests".to_string(), "1".to_string());
-        headers.insert("Priority".to_string(), "u=0, i".to_string());
+        headers.append("Accept", "text/html,application/xhtml+xml,application/xml;q=0.9,image/avif,image/webp,image/png,image/svg+xml,*/*;q=0.8");
+        headers.append("Accept-Language", "en-US,en;q=0.5");
+        headers.append("Accept-Encoding", "gzip, deflate");
+        headers.append("Content-Type", "application/x-www-form-urlencoded");
+        headers.append("Content-Length", "12");
+        headers.append("Origin", "http://192.168.4.28:1234");
+        headers.append("Connection", "keep-alive");
+        headers.append("Referer", "http://192.168.4.28:1234/");
+        headers.append("Upgrade-Insecure-Requests", "1");
+        headers.append("Priority", "u=0, i");
 
         let control = HttpRequest {
             method: "POST".to_string(),
@@ -319,10 +1103,12 @@ mod tests {
             http_version: "HTTP/1.1".to_string(),
             headers,
             body: Some("username=asd".to_string()),
+            peer_addr: None,
             querys: None,
+            params: HashMap::new(),
         };
 
-        assert_eq!(control, HttpRequest::parse(&request_str));
+        assert_eq!(control, HttpRequest::parse(&request_str).unwrap());
     }
 
     #[test]
@@ -346,26 +1132,22 @@ mod tests {
             request_str.push_str("\r\n");
         }
 
-        let mut headers = HashMap::new();
+        let mut headers = Headers::new();
 
-        headers.insert("Host".to_string(), "192.168.4.28:1234".to_string());
-        headers.insert(
-            "User-Agent".to_string(),
-            "Mozilla/5.0 (X11; Linux x86_64; rv:131.0) Gecko/20100101 Firefox/131.0".to_string(),
+        headers.append("Host", "192.168.4.28:1234");
+        headers.append(
+            "User-Agent",
+            "Mozilla/5.0 (X11; Linux x86_64; rv:131.0) Gecko/20100101 Firefox/131.0",
         );
-        headers.insert(
-            "Accept".to_string(),
-            "image/avif,image/webp,image/png,image/svg+xml,image/*;q=0.8,*/*;q=0.5".to_string(),
+        headers.append(
+            "Accept",
+            "image/avif,image/webp,image/png,image/svg+xml,image/*;q=0.8,*/*;q=0.5",
         );
-        headers.insert("Accept-Language".to_string(), "en-US,en;q=0.5".to_string());
-        headers.insert("Accept-Encoding".to_string(), "gzip, deflate".to_string());
-        headers.insert("Connection".to_string(), "keep-alive".to_string());
-        headers.insert("Connection".to_string(), "keep-alive".to_string());
-        headers.insert(
-            "Referer".to_string(),
-            "http://192.168.4.28:1234/".to_string(),
-        );
-        headers.insert("Priority".to_string(), "u=6".to_string());
+        headers.append("Accept-Language", "en-US,en;q=0.5");
+        headers.append("Accept-Encoding", "gzip, deflate");
+        headers.append("Connection", "keep-alive");
+        headers.append("Referer", "http://192.168.4.28:1234/");
+        headers.append("Priority", "u=6");
 
         request_str.push_str("\r\n");
 
@@ -379,10 +1161,12 @@ mod tests {
             http_version: "HTTP/1.1".to_string(),
             headers,
             body: None,
+            peer_addr: None,
             querys: Some(querys),
+            params: HashMap::new(),
         };
 
-        assert_eq!(control, HttpRequest::parse(&request_str));
+        assert_eq!(control, HttpRequest::parse(&request_str).unwrap());
     }
 
     #[test]
@@ -406,26 +1190,22 @@ mod tests {
             request_str.push_str("\r\n");
         }
 
-        let mut headers = HashMap::new();
+        let mut headers = Headers::new();
 
-        headers.insert("Host".to_string(), "192.168.4.28:1234".to_string());
-        headers.insert(
-            "User-Agent".to_string(),
-            "Mozilla/5.0 (X11; Linux x86_64; rv:131.0) Gecko/20100101 Firefox/131.0".to_string(),
-        );
-        headers.insert(
-            "Accept".to_string(),
-            "image/avif,image/webp,image/png,image/svg+xml,image/*;q=0.8,*/*;q=0.5".to_string(),
+        headers.append("Host", "192.168.4.28:1234");
+        headers.append(
+            "User-Agent",
+            "Mozilla/5.0 (X11; Linux x86_64; rv:131.0) Gecko/20100101 Firefox/131.0",
         );
-        headers.insert("Accept-Language".to_string(), "en-US,en;q=0.5".to_string());
-        headers.insert("Accept-Encoding".to_string(), "gzip, deflate".to_string());
-        headers.insert("Connection".to_string(), "keep-alive".to_string());
-        headers.insert("Connection".to_string(), "keep-alive".to_string());
-        headers.insert(
-            "Referer".to_string(),
-            "http://192.168.4.28:1234/".to_string(),
+        headers.append(
+            "Accept",
+            "image/avif,image/webp,image/png,image/svg+xml,image/*;q=0.8,*/*;q=0.5",
         );
-        headers.insert("Priority".to_string(), "u=6".to_string());
+        headers.append("Accept-Language", "en-US,en;q=0.5");
+        headers.append("Accept-Encoding", "gzip, deflate");
+        headers.append("Connection", "keep-alive");
+        headers.append("Referer", "http://192.168.4.28:1234/");
+        headers.append("Priority", "u=6");
 
         request_str.push_str("\r\n");
 
@@ -440,10 +1220,12 @@ mod tests {
             http_version: "HTTP/1.1".to_string(),
             headers,
             body: None,
+            peer_addr: None,
             querys: Some(querys),
+            params: HashMap::new(),
         };
 
-        assert_eq!(control, HttpRequest::parse(&request_str));
+        assert_eq!(control, HttpRequest::parse(&request_str).unwrap());
     }
 
     #[test]
@@ -460,4 +1242,487 @@ mod tests {
 
         assert_eq!(control, http_response.build().to_string());
     }
+
+    fn parse_error(raw_request: &str) -> ParseError {
+        HttpRequest::parse(raw_request).unwrap_err()
+    }
+
+    #[test]
+    fn test_parse_request_missing_request_line() {
+        assert_eq!(ParseError::MissingRequestLine, parse_error(""));
+        assert_eq!(
+            ParseError::MissingRequestLine,
+            parse_error("\r\nHost: localhost\r\n\r\n")
+        );
+    }
+
+    #[test]
+    fn test_parse_request_malformed_request_line() {
+        assert_eq!(
+            ParseError::MalformedRequestLine("GET /".to_string()),
+            parse_error("GET /\r\nHost: localhost\r\n\r\n")
+        );
+        assert_eq!(
+            ParseError::MalformedRequestLine("GET / HTTP/1.1 extra".to_string()),
+            parse_error("GET / HTTP/1.1 extra\r\n\r\n")
+        );
+    }
+
+    #[test]
+    fn test_parse_request_malformed_header() {
+        assert_eq!(
+            ParseError::MalformedHeader("Host 192.168.4.28".to_string()),
+            parse_error("GET / HTTP/1.1\r\nHost 192.168.4.28\r\n\r\n")
+        );
+        assert_eq!(
+            ParseError::MalformedHeader(": value".to_string()),
+            parse_error("GET / HTTP/1.1\r\n: value\r\n\r\n")
+        );
+    }
+
+    #[test]
+    fn test_parse_request_malformed_query() {
+        assert_eq!(
+            ParseError::MalformedQuery("time".to_string()),
+            parse_error("GET /favicon.ico?user=unknown&time HTTP/1.1\r\n\r\n")
+        );
+    }
+
+    #[test]
+    fn test_parse_request_invalid_content_length() {
+        assert_eq!(
+            ParseError::InvalidContentLength("twelve".to_string()),
+            parse_error("POST /login HTTP/1.1\r\nContent-Length: twelve\r\n\r\nusername=asd")
+        );
+    }
+
+    #[test]
+    fn test_parse_request_incomplete_body() {
+        assert_eq!(
+            ParseError::IncompleteBody {
+                expected: 20,
+                received: 12
+            },
+            parse_error("POST /login HTTP/1.1\r\nContent-Length: 20\r\n\r\nusername=asd")
+        );
+        assert_eq!(
+            ParseError::InvalidEncoding,
+            parse_error("POST /login HTTP/1.1\r\nContent-Length: 10\r\n\r\nusername=é")
+        );
+    }
+
+    /// Hands out at most `chunk` bytes per read to simulate a slow client.
+    struct Trickle<'a> {
+        data: &'a [u8],
+        chunk: usize,
+    }
+
+    impl Read for Trickle<'_> {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            let len = self.chunk.min(buf.len()).min(self.data.len());
+
+            buf[..len].copy_from_slice(&self.data[..len]);
+            self.data = &self.data[len..];
+
+            Ok(len)
+        }
+    }
+
+    #[test]
+    fn test_read_request_across_reads() {
+        let body = "m".repeat(3000);
+        let raw = format!(
+            "POST /message HTTP/1.1\r\nContent-Length: {}\r\n\r\n{}",
+            body.len(),
+            body
+        );
+
+        let mut reader = RequestReader::new(
+            Trickle {
+                data: raw.as_bytes(),
+                chunk: 7,
+            },
+            RequestLimits::default(),
+        );
+
+        let request = reader.read_request().unwrap();
+
+        assert_eq!(Some(body), request.body);
+        assert!(matches!(
+            reader.read_request(),
+            Err(ReadError::ConnectionClosed)
+        ));
+    }
+
+    #[test]
+    fn test_read_request_header_too_large() {
+        let raw = format!("GET / HTTP/1.1\r\nCookie: {}\r\n\r\n", "c".repeat(200));
+
+        let mut reader = RequestReader::new(
+            raw.as_bytes(),
+            RequestLimits {
+                max_header_size: 64,
+                max_body_size: 64,
+            },
+        );
+
+        assert!(matches!(
+            reader.read_request(),
+            Err(ReadError::HeaderTooLarge)
+        ));
+    }
+
+    #[test]
+    fn test_read_request_body_too_large() {
+        let raw = "POST /message HTTP/1.1\r\nContent-Length: 65\r\n\r\n";
+
+        let mut reader = RequestReader::new(
+            raw.as_bytes(),
+            RequestLimits {
+                max_header_size: 64,
+                max_body_size: 64,
+            },
+        );
+
+        assert!(matches!(
+            reader.read_request(),
+            Err(ReadError::BodyTooLarge)
+        ));
+    }
+
+    #[test]
+    fn test_read_request_truncated_body() {
+        let raw = "POST /message HTTP/1.1\r\nContent-Length: 12\r\n\r\nshort";
+
+        let mut reader = RequestReader::new(raw.as_bytes(), RequestLimits::default());
+
+        assert!(matches!(reader.read_request(), Err(ReadError::Io(_))));
+    }
+
+    #[test]
+    fn test_headers_case_insensitive() {
+        let request = HttpRequest::parse(
+            "POST /message HTTP/1.1\r\ncookie: username=asd\r\ncontent-length: 5\r\n\r\nhello",
+        )
+        .unwrap();
+
+        assert_eq!(Some("username=asd"), request.get_header("Cookie"));
+        assert_eq!(Some("username=asd"), request.get_header("COOKIE"));
+        assert_eq!(Some("hello".to_string()), request.body);
+    }
+
+    #[test]
+    fn test_headers_multiple_values() {
+        let request =
+            HttpRequest::parse("GET / HTTP/1.1\r\nAccept: text/html\r\naccept: text/css\r\n\r\n")
+                .unwrap();
+
+        assert_eq!(Some("text/html"), request.get_header("Accept"));
+        assert_eq!(
+            vec!["text/html", "text/css"],
+            request.headers().get_all("accept").collect::<Vec<&str>>()
+        );
+    }
+
+    #[test]
+    fn test_headers_insert_replaces_in_place() {
+        let mut headers = Headers::new();
+
+        headers.append("Content-Type", "text/html");
+        headers.append("X-Value", "1");
+        headers.append("Content-Length", "10");
+        headers.append("x-value", "2");
+
+        headers.insert("X-VALUE", "3");
+
+        assert_eq!(
+            vec![
+                ("Content-Type", "text/html"),
+                ("X-Value", "3"),
+                ("Content-Length", "10")
+            ],
+            headers.iter().collect::<Vec<(&str, &str)>>()
+        );
+
+        headers.remove("content-type");
+
+        assert!(!headers.contains("Content-Type"));
+        assert_eq!(2, headers.len());
+    }
+
+    #[test]
+    fn test_http_response_multiple_cookies() {
+        let http_response = HttpResponse::builder()
+            .http_version("HTTP/1.1")
+            .status_code(303)
+            .status_message("See Other")
+            .add_cookie("username=asdf")
+            .add_cookie("theme=dark")
+            .add_header("Content-Length", "0");
+
+        let control = "HTTP/1.1 303 See Other\r\nSet-Cookie: username=asdf\r\nSet-Cookie: theme=dark\r\nContent-Length: 0\r\n\r\n".to_string();
+
+        assert_eq!(control, http_response.build().to_string());
+    }
+
+    #[test]
+    fn test_set_cookie_attributes() {
+        let session = Cookie::new("session", "abc123")
+            .path("/")
+            .max_age(3600)
+            .http_only(true)
+            .secure(true)
+            .same_site(SameSite::Strict);
+
+        assert_eq!(
+            "session=abc123; Path=/; Max-Age=3600; HttpOnly; Secure; SameSite=Strict",
+            session.to_string()
+        );
+
+        assert_eq!(
+            "a%3Bb%20c=1%2C%202%3B%20Max-Age%3D0%0D%0AX%3A%20y",
+            Cookie::new("a;b c", "1, 2; Max-Age=0\r\nX: y").to_string()
+        );
+
+        let http_response = HttpResponse::builder()
+            .status_code(303)
+            .status_message("See Other")
+            .set_cookie(&Cookie::removal("session").path("/"))
+            .build();
+
+        assert_eq!(
+            Some("session=; Path=/; Max-Age=0"),
+            http_response.headers().get("Set-Cookie")
+        );
+    }
+
+    #[test]
+    fn test_request_cookies() {
+        let request = HttpRequest::parse(
+            "GET / HTTP/1.1\r\nCookie: theme=dark; session=abc=123\r\nCookie: lang=en\r\n\r\n",
+        )
+        .unwrap();
+
+        assert_eq!(Some("dark"), request.cookie("theme"));
+        assert_eq!(Some("abc=123"), request.cookie("session"));
+        assert_eq!(Some("en"), request.cookie("lang"));
+        assert_eq!(None, request.cookie("missing"));
+    }
+
+    #[test]
+    fn test_percent_decode() {
+        assert_eq!("Ana María", percent_decode("Ana Mar%C3%ADa"));
+        assert_eq!("100%", percent_decode("100%"));
+        assert_eq!("%zz", percent_decode("%zz"));
+        assert_eq!("%+1", percent_decode("%+1"));
+        assert_eq!("%-f", percent_decode("%-f"));
+        assert_eq!("a+b", percent_decode("a+b"));
+    }
+
+    #[test]
+    fn test_percent_encode() {
+        assert_eq!("Ana%20Mar%C3%ADa", percent_encode("Ana María"));
+        assert_eq!("Ana María", percent_decode(&percent_encode("Ana María")));
+    }
+
+    #[test]
+    fn test_parse_form() {
+        let form = parse_form("username=Ana+Mar%C3%ADa&equation=1%2B1%3D2&token=a=b&").unwrap();
+
+        assert_eq!("Ana María", form["username"]);
+        assert_eq!("1+1=2", form["equation"]);
+        assert_eq!("a=b", form["token"]);
+        assert_eq!(3, form.len());
+    }
+
+    #[test]
+    fn test_parse_request_encoded_query_parameters() {
+        let request =
+            HttpRequest::parse("GET /chat?room=Game%20Night&user=Ana+Mar%C3%ADa HTTP/1.1\r\n\r\n")
+                .unwrap();
+
+        assert_eq!(Some("Game Night"), request.query("room"));
+        assert_eq!(Some("Ana María"), request.query("user"));
+        assert_eq!(None, request.query("time"));
+    }
+
+    #[test]
+    fn test_request_form() {
+        let request = HttpRequest::parse(
+            "POST /login HTTP/1.1\r\nContent-Length: 26\r\n\r\nusername=Ana+Mar%C3%ADa%3D",
+        )
+        .unwrap();
+
+        assert_eq!("Ana María=", request.form().unwrap()["username"]);
+    }
+
+    #[test]
+    fn test_http_response_binary_body() {
+        let png_signature = vec![0x89, b'P', b'N', b'G', b'\r', b'\n', 0x1a, b'\n', 0xff];
+
+        let http_response = HttpResponse::builder()
+            .http_version("HTTP/1.1")
+            .status_code(200)
+            .status_message("OK")
+            .add_header("Content-Type", "image/png")
+            .bytes(png_signature.clone())
+            .build();
+
+        let mut control =
+            b"HTTP/1.1 200 OK\r\nContent-Type: image/png\r\nContent-Length: 9\r\n\r\n".to_vec();
+        control.extend_from_slice(&png_signature);
+
+        assert_eq!(control, http_response.to_bytes());
+
+        let mut written = Vec::new();
+        http_response.write_to(&mut written).unwrap();
+
+        assert_eq!(control, written);
+    }
+
+    #[test]
+    fn test_http_response_content_length() {
+        let with_body = HttpResponse::builder()
+            .status_code(200)
+            .status_message("OK")
+            .add_header("Content-Length", "3")
+            .body("María")
+            .build();
+
+        let without_body = HttpResponse::builder()
+            .status_code(200)
+            .status_message("OK")
+            .build();
+
+        let not_modified = HttpResponse::builder()
+            .status_code(304)
+            .status_message("Not Modified")
+            .build();
+
+        assert_eq!(Some("6"), with_body.headers().get("Content-Length"));
+        assert_eq!(Some("0"), without_body.headers().get("Content-Length"));
+        assert_eq!(None, not_modified.headers().get("Content-Length"));
+    }
+
+    #[test]
+    fn test_read_pipelined_requests() {
+        let raw = concat!(
+            "POST /message HTTP/1.1\r\nContent-Length: 5\r\n\r\nhello",
+            "GET /new-message HTTP/1.1\r\nHost: localhost\r\n\r\n",
+            "POST /message HTTP/1.1\r\nContent-Length: 3\r\n\r\nbye",
+        );
+
+        let mut reader = RequestReader::new(
+            Trickle {
+                data: raw.as_bytes(),
+                chunk: 4096,
+            },
+            RequestLimits::default(),
+        );
+
+        let first = reader.read_request().unwrap();
+        let second = reader.read_request().unwrap();
+        let third = reader.read_request().unwrap();
+
+        assert_eq!(Some("hello".to_string()), first.body);
+        assert_eq!("/new-message", second.resource);
+        assert_eq!(None, second.body);
+        assert_eq!(Some("bye".to_string()), third.body);
+        assert!(matches!(
+            reader.read_request(),
+            Err(ReadError::ConnectionClosed)
+        ));
+    }
+
+    #[test]
+    fn test_request_keep_alive() {
+        let keep_alive = |raw: &str| HttpRequest::parse(raw).unwrap().keep_alive();
+
+        assert!(keep_alive("GET / HTTP/1.1\r\n\r\n"));
+        assert!(keep_alive(
+            "GET / HTTP/1.1\r\nConnection: keep-alive\r\n\r\n"
+        ));
+        assert!(!keep_alive("GET / HTTP/1.1\r\nConnection: close\r\n\r\n"));
+        assert!(!keep_alive(
+            "GET / HTTP/1.1\r\nconnection: Upgrade, Close\r\n\r\n"
+        ));
+        assert!(!keep_alive("GET / HTTP/1.0\r\n\r\n"));
+        assert!(keep_alive(
+            "GET / HTTP/1.0\r\nConnection: keep-alive\r\n\r\n"
+        ));
+    }
+
+    #[test]
+    fn test_http_response_streaming_body() {
+        let http_response = HttpResponse::builder()
+            .http_version("HTTP/1.1")
+            .status_code(200)
+            .status_message("OK")
+            .add_header("Content-Type", "text/event-stream")
+            .stream(|writer| {
+                writer.write_all(b"first\n")?;
+                writer.write_all(b"second\n")
+            })
+            .build();
+
+        assert!(http_response.is_streaming());
+        assert_eq!(None, http_response.headers().get("Content-Length"));
+
+        let mut written = Vec::new();
+        http_response.write_to(&mut written).unwrap();
+
+        assert_eq!(
+            "HTTP/1.1 200 OK\r\nContent-Type: text/event-stream\r\n\r\nfirst\nsecond\n",
+            String::from_utf8(written).unwrap()
+        );
+    }
+
+    #[test]
+    fn test_upgrade_reads_past_request() {
+        let mut http_response = HttpResponse::builder()
+            .status_code(101)
+            .status_message("Switching Protocols")
+            .upgrade(|reader, writer| {
+                let mut data = String::new();
+                reader.read_to_string(&mut data)?;
+
+                writer.write_all(data.to_uppercase().as_bytes())
+            })
+            .build();
+
+        assert_eq!(None, http_response.headers().get("Content-Length"));
+
+        let raw = "GET /ws HTTP/1.1\r\nUpgrade: echo\r\n\r\nhello";
+        let mut reader = RequestReader::new(raw.as_bytes(), RequestLimits::default());
+
+        reader.read_request().unwrap();
+
+        let upgrade = http_response.take_upgrade().unwrap();
+        assert!(http_response.take_upgrade().is_none());
+
+        let mut written = Vec::new();
+        upgrade(&mut reader, &mut written).unwrap();
+
+        assert_eq!(b"HELLO", &written[..]);
+    }
+
+    #[test]
+    fn test_server_sent_event() {
+        assert_eq!(
+            "id: 3\nevent: message\ndata: {\"a\": 1}\n\n",
+            server_sent_event(Some(3), "message", "{\"a\": 1}")
+        );
+        assert_eq!(
+            "event: notice\ndata: one\ndata: two\n\n",
+            server_sent_event(None, "notice", "one\ntwo")
+        );
+        assert_eq!(
+            "event: ping\ndata: \n\n",
+            server_sent_event(None, "ping", "")
+        );
+        assert_eq!(
+            "event: notice\ndata: one\ndata: \n\n",
+            server_sent_event(None, "notice", "one\r\n")
+        );
+    }
 }
@@ -0,0 +1,309 @@
+use std::io::{self, Write};
+
+use flate2::write::{GzEncoder, ZlibEncoder};
+use flate2::Compression;
+
+use crate::http::{Headers, HttpRequest, HttpResponse};
+
+/// Bodies smaller than this are sent as they are unless told otherwise, as
+/// compressing them saves less than it costs.
+pub const DEFAULT_MIN_SIZE: usize = 1024;
+
+/// The content codings the server can compress bodies with.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Encoding {
+    Gzip,
+    /// The zlib format, which is what HTTP calls `deflate`.
+    Deflate,
+}
+
+impl Encoding {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::Gzip => "gzip",
+            Self::Deflate => "deflate",
+        }
+    }
+
+    /// Picks the encoding the client ranks highest in its `Accept-Encoding`
+    /// header, preferring gzip when it ranks both the same.
+    pub fn negotiate(accept_encoding: &str) -> Option<Self> {
+        let mut gzip = None;
+        let mut deflate = None;
+        let mut any = None;
+
+        for item in accept_encoding.split(',') {
+            let mut parts = item.split(';').map(str::trim);
+
+            let coding = parts.next().unwrap_or("").to_ascii_lowercase();
+
+            let quality = match parts.find_map(|p| p.strip_prefix("q=")) {
+                Some(q) => match q.parse::<f32>() {
+                    Ok(q) if (0.0..=1.0).contains(&q) => q,
+                    _ => continue,
+                },
+                None => 1.0,
+            };
+
+            match coding.as_str() {
+                "gzip" | "x-gzip" => gzip = Some(quality),
+                "deflate" => deflate = Some(quality),
+                "*" => any = Some(quality),
+                _ => (),
+            }
+        }
+
+        // A wildcard covers the codings that are not named
+        let gzip = gzip.or(any).unwrap_or(0.0);
+        let deflate = deflate.or(any).unwrap_or(0.0);
+
+        match (gzip, deflate) {
+            (0.0, 0.0) => None,
+            (gzip, deflate) if gzip >= deflate => Some(Self::Gzip),
+            _ => Some(Self::Deflate),
+        }
+    }
+
+    pub fn compress(&self, data: &[u8]) -> io::Result<Vec<u8>> {
+        match self {
+            Self::Gzip => {
+                let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+
+                encoder.write_all(data)?;
+                encoder.finish()
+            }
+            Self::Deflate => {
+                let mut encoder = ZlibEncoder::new(Vec::new(), Compression::default());
+
+                encoder.write_all(data)?;
+                encoder.finish()
+            }
+        }
+    }
+}
+
+/// Whether bodies of the type shrink when compressed. Images, fonts and the
+/// like are compressed already.
+pub fn is_compressible(content_type: &str) -> bool {
+    let essence = content_type
+        .split(';')
+        .next()
+        .unwrap_or("")
+        .trim()
+        .to_ascii_lowercase();
+
+    essence.starts_with("text/")
+        || essence.ends_with("+json")
+        || essence.ends_with("+xml")
+        || matches!(
+            essence.as_str(),
+            "application/json" | "application/javascript" | "application/xml" | "application/wasm"
+        )
+}
+
+/// Tells caches that the body depends on the `Accept-Encoding` header.
+pub fn add_vary(headers: &mut Headers) {
+    let vary = match headers.get("Vary") {
+        Some(vary)
+            if vary
+                .split(',')
+                .any(|v| v.trim().eq_ignore_ascii_case("Accept-Encoding")) =>
+        {
+            return;
+        }
+        Some(vary) => format!("{vary}, Accept-Encoding"),
+        None => "Accept-Encoding".to_string(),
+    };
+
+    headers.insert("Vary", &vary);
+}
+
+/// Turns a strong ETag into a weak one. The compressed body is a different
+/// sequence of bytes, but it still stands for the same content.
+pub fn weaken_etag(etag: &str) -> String {
+    match etag.starts_with("W/") {
+        true => etag.to_string(),
+        false => format!("W/{etag}"),
+    }
+}
+
+/// Whether the response's body is worth compressing, whatever the client
+/// accepts.
+fn should_compress(response: &HttpResponse, min_size: usize) -> bool {
+    let headers = &response.headers;
+
+    // A range is a slice of the uncompressed body
+    response.stream.is_none()
+        && response.upgrade.is_none()
+        && !headers.contains("Content-Encoding")
+        && !headers.contains("Content-Range")
+        && headers.get("Content-Type").is_some_and(is_compressible)
+        && response.body.as_ref().is_some_and(|b| b.len() >= min_size)
+}
+
+/// Replaces the body with one compressed in the encoding.
+fn set_encoded_body(response: &mut HttpResponse, encoding: Encoding, body: Vec<u8>) {
+    let headers = &mut response.headers;
+
+    headers.insert("Content-Encoding", encoding.name());
+    headers.insert("Content-Length", &body.len().to_string());
+
+    if let Some(etag) = headers.get("ETag").map(weaken_etag) {
+        headers.insert("ETag", &etag);
+    }
+
+    response.body = Some(body);
+}
+
+/// Compresses the response's body in the encoding the client accepts best,
+/// if it is text of at least `min_size` bytes.
+pub fn compress_response(request: &HttpRequest, response: &mut HttpResponse, min_size: usize) {
+    if !should_compress(response, min_size) {
+        return;
+    }
+
+    add_vary(&mut response.headers);
+
+    let Some(encoding) = request
+        .get_header("Accept-Encoding")
+        .and_then(Encoding::negotiate)
+    else {
+        return;
+    };
+
+    let body = response.body.as_deref().unwrap_or_default();
+
+    match encoding.compress(body) {
+        Ok(compressed) if compressed.len() < body.len() => {
+            set_encoded_body(response, encoding, compressed)
+        }
+        Ok(_) => (),
+        Err(e) => eprintln!("Encountered error compressing response: {e}"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use flate2::read::{GzDecoder, ZlibDecoder};
+    use std::io::Read;
+
+    fn request(accept_encoding: Option<&str>) -> HttpRequest {
+        let mut head = "GET / HTTP/1.1\r\nHost: localhost\r\n".to_string();
+
+        if let Some(accept_encoding) = accept_encoding {
+            head.push_str(&format!("Accept-Encoding: {accept_encoding}\r\n"));
+        }
+
+        HttpRequest::parse_head(&head).unwrap()
+    }
+
+    fn response(content_type: &str, body: &str) -> HttpResponse {
+        HttpResponse::builder()
+            .http_version("HTTP/1.1")
+            .status_code(200)
+            .status_message("OK")
+            .add_header("Content-Type", content_type)
+            .add_header("ETag", "\"abc\"")
+            .body(body)
+            .build()
+    }
+
+    #[test]
+    fn test_negotiate() {
+        assert_eq!(Some(Encoding::Gzip), Encoding::negotiate("gzip, deflate"));
+        assert_eq!(Some(Encoding::Gzip), Encoding::negotiate("deflate, GZIP"));
+        assert_eq!(
+            Some(Encoding::Deflate),
+            Encoding::negotiate("gzip;q=0.5, deflate")
+        );
+        assert_eq!(Some(Encoding::Deflate), Encoding::negotiate("br, deflate"));
+        assert_eq!(Some(Encoding::Gzip), Encoding::negotiate("*"));
+        assert_eq!(
+            Some(Encoding::Deflate),
+            Encoding::negotiate("gzip;q=0, *;q=0.1")
+        );
+        assert_eq!(None, Encoding::negotiate("gzip;q=0, deflate;q=0"));
+        assert_eq!(None, Encoding::negotiate("identity, br"));
+        assert_eq!(None, Encoding::negotiate("gzip;q=2"));
+        assert_eq!(None, Encoding::negotiate(""));
+    }
+
+    #[test]
+    fn test_compressible_types() {
+        assert!(is_compressible("text/html; charset=utf-8"));
+        assert!(is_compressible("application/json"));
+        assert!(is_compressible("image/svg+xml"));
+        assert!(is_compressible("application/manifest+json"));
+
+        assert!(!is_compressible("image/png"));
+        assert!(!is_compressible("font/woff2"));
+        assert!(!is_compressible("application/octet-stream"));
+    }
+
+    #[test]
+    fn test_compress_response() {
+        let body = "Hello there! ".repeat(200);
+
+        let mut gzipped = response("text/plain", &body);
+
+        compress_response(&request(Some("gzip, deflate")), &mut gzipped, 1024);
+
+        assert_eq!(Some("gzip"), gzipped.headers().get("Content-Encoding"));
+        assert_eq!(Some("Accept-Encoding"), gzipped.headers().get("Vary"));
+        assert_eq!(Some("W/\"abc\""), gzipped.headers().get("ETag"));
+        assert_eq!(
+            Some(gzipped.body().unwrap().len().to_string().as_str()),
+            gzipped.headers().get("Content-Length")
+        );
+
+        let mut decoded = String::new();
+
+        GzDecoder::new(gzipped.body().unwrap())
+            .read_to_string(&mut decoded)
+            .unwrap();
+
+        assert_eq!(body, decoded);
+
+        let mut deflated = response("text/plain", &body);
+
+        compress_response(&request(Some("deflate")), &mut deflated, 1024);
+
+        assert_eq!(Some("deflate"), deflated.headers().get("Content-Encoding"));
+
+        let mut decoded = String::new();
+
+        ZlibDecoder::new(deflated.body().unwrap())
+            .read_to_string(&mut decoded)
+            .unwrap();
+
+        assert_eq!(body, decoded);
+    }
+
+    #[test]
+    fn test_responses_left_alone() {
+        let body = "Hello there! ".repeat(200);
+
+        // The client does not accept any, but caches still need to know
+        let mut identity = response("text/plain", &body);
+
+        compress_response(&request(None), &mut identity, 1024);
+
+        assert_eq!(None, identity.headers().get("Content-Encoding"));
+        assert_eq!(Some("Accept-Encoding"), identity.headers().get("Vary"));
+        assert_eq!(Some(body.as_bytes()), identity.body());
+
+        let mut small = response("text/plain", "Hello there!");
+
+        compress_response(&request(Some("gzip")), &mut small, 1024);
+
+        assert_eq!(None, small.headers().get("Content-Encoding"));
+        assert_eq!(None, small.headers().get("Vary"));
+
+        let mut image = response("image/png", &body);
+
+        compress_response(&request(Some("gzip")), &mut image, 1024);
+
+        assert_eq!(None, image.headers().get("Content-Encoding"));
+    }
+}
@@ -0,0 +1,811 @@
+use std::borrow::Cow;
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{self, Read};
+use std::path::{Component, Path, PathBuf};
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use sha1_smol::Sha1;
+
+use crate::assets;
+use crate::http::compression::{self, is_compressible, Encoding};
+use crate::http::{
+    get_mime_type, mime_type_for, sniff_mime_type, HttpRequest, HttpResponse, DEFAULT_MIME_TYPE,
+};
+
+/// The page shown for files that do not exist, looked up in the root.
+const NOT_FOUND_PAGE: &str = "404.html";
+
+/// How much of a file is looked at to guess what it is.
+const SNIFF_LENGTH: usize = 512;
+
+const WEEKDAYS: [&str; 7] = ["Thu", "Fri", "Sat", "Sun", "Mon", "Tue", "Wed"];
+
+const MONTHS: [&str; 12] = [
+    "Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec",
+];
+
+/// Converts days since the Unix epoch to a year, month and day.
+fn civil_from_days(days: i64) -> (i64, u32, u32) {
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z.rem_euclid(146097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+
+    (
+        yoe + era * 400 + i64::from(month <= 2),
+        month as u32,
+        day as u32,
+    )
+}
+
+/// Converts a year, month and day to days since the Unix epoch.
+fn days_from_civil(year: i64, month: u32, day: u32) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let yoe = year.rem_euclid(400);
+    let month = i64::from(month);
+    let doy = (153 * (if month > 2 { month - 3 } else { month + 9 }) + 2) / 5 + i64::from(day) - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+
+    era * 146097 + doe - 719468
+}
+
+/// Formats the time as an HTTP date, e.g. `Sun, 06 Nov 1994 08:49:37 GMT`.
+pub fn http_date(time: SystemTime) -> String {
+    let secs = time.duration_since(UNIX_EPOCH).map_or(0, |d| d.as_secs());
+
+    let days = secs / 86400;
+    let (year, month, day) = civil_from_days(days as i64);
+
+    format!(
+        "{}, {:02} {} {} {:02}:{:02}:{:02} GMT",
+        WEEKDAYS[(days % 7) as usize],
+        day,
+        MONTHS[month as usize - 1],
+        year,
+        secs % 86400 / 3600,
+        secs % 3600 / 60,
+        secs % 60
+    )
+}
+
+/// Parses an HTTP date in the format `http_date` writes. The obsolete
+/// formats are not accepted, which makes the header they are in ignored.
+pub fn parse_http_date(date: &str) -> Option<SystemTime> {
+    let (_, date) = date.split_once(", ")?;
+
+    let parts: Vec<&str> = date.split(' ').collect();
+
+    let [day, month, year, time, "GMT"] = parts[..] else {
+        return None;
+    };
+
+    let month = MONTHS.iter().position(|m| *m == month)? as u32 + 1;
+
+    let time: Vec<u64> = time
+        .split(':')
+        .map(|t| t.parse().ok())
+        .collect::<Option<_>>()?;
+
+    let [hours, minutes, seconds] = time[..] else {
+        return None;
+    };
+
+    let days = days_from_civil(year.parse().ok()?, month, day.parse().ok()?);
+
+    let secs = u64::try_from(days).ok()? * 86400 + hours * 3600 + minutes * 60 + seconds;
+
+    Some(UNIX_EPOCH + Duration::from_secs(secs))
+}
+
+/// What part of a file a `Range` header asks for.
+#[derive(Debug, PartialEq, Eq)]
+enum ByteRange {
+    /// The whole file, because there was no header or it could not be
+    /// understood.
+    Full,
+    /// The first and last byte, inclusive.
+    Partial(u64, u64),
+    Unsatisfiable,
+}
+
+impl ByteRange {
+    /// Parses a single range of bytes. Several ranges at once are answered
+    /// with the whole file, which clients have to accept.
+    fn parse(header: Option<&str>, len: u64) -> Self {
+        let Some((start, end)) = header
+            .and_then(|h| h.trim().strip_prefix("bytes="))
+            .filter(|r| !r.contains(','))
+            .and_then(|r| r.trim().split_once('-'))
+        else {
+            return Self::Full;
+        };
+
+        let (start, end) = match (start.parse::<u64>(), end.parse::<u64>()) {
+            // The last `end` bytes
+            (Err(_), Ok(suffix)) if start.is_empty() => {
+                if suffix == 0 || len == 0 {
+                    return Self::Unsatisfiable;
+                }
+
+                (len.saturating_sub(suffix), len - 1)
+            }
+            (Ok(start), Err(_)) if end.is_empty() => (start, len.saturating_sub(1)),
+            (Ok(start), Ok(end)) if start <= end => (start, end.min(len.saturating_sub(1))),
+            _ => return Self::Full,
+        };
+
+        match start < len {
+            true => Self::Partial(start, end),
+            false => Self::Unsatisfiable,
+        }
+    }
+}
+
+/// A file's content along with what tells whether a client's copy of it is
+/// still fresh.
+struct Asset {
+    content: Cow<'static, [u8]>,
+    etag: String,
+    /// Only known for files on disk.
+    modified: Option<SystemTime>,
+}
+
+/// The binary's files never change, so their content identifies them.
+fn embedded_etag(content: &[u8]) -> String {
+    let digest = Sha1::from(content).digest().to_string();
+
+    format!("\"{}\"", &digest[..16])
+}
+
+impl Asset {
+    fn read(path: &Path) -> io::Result<Self> {
+        let mut file = File::open(path)?;
+
+        let metadata = file.metadata()?;
+        let modified = metadata.modified()?;
+
+        let mut content = Vec::with_capacity(metadata.len() as usize);
+
+        file.read_to_end(&mut content)?;
+
+        let etag = format!(
+            "\"{:x}-{:x}\"",
+            content.len(),
+            modified
+                .duration_since(UNIX_EPOCH)
+                .map_or(0, |d| d.as_nanos())
+        );
+
+        Ok(Self {
+            content: Cow::Owned(content),
+            etag,
+            modified: Some(modified),
+        })
+    }
+}
+
+/// A file's body compressed, along with the ETag of the version of the file
+/// that was compressed. There is no body when compressing did not make the
+/// file any smaller.
+struct Compressed {
+    etag: String,
+    body: Option<Vec<u8>>,
+}
+
+/// Whether the path is of a page, which are templates rather than files to be
+/// served.
+fn is_page(path: &str) -> bool {
+    Path::new(path)
+        .extension()
+        .is_some_and(|ext| ext.eq_ignore_ascii_case("html") || ext.eq_ignore_ascii_case("htm"))
+}
+
+/// Checks that the path stays below whatever it is joined onto, returning it
+/// without any `.` segments.
+fn relative_path(path: &str) -> Option<PathBuf> {
+    let mut relative = PathBuf::new();
+
+    for component in Path::new(path).components() {
+        match component {
+            Component::Normal(segment) => relative.push(segment),
+            Component::CurDir => (),
+            // Leading slashes or drive letters would replace what it is
+            // joined onto
+            _ => return None,
+        }
+    }
+
+    Some(relative)
+}
+
+/// Serves the files embedded in the binary, or those in a directory on disk
+/// which shadow the embedded ones, never anything outside of that directory.
+/// Pages are templates that are filled in before they are served, so they are
+/// never served as they are.
+/// Responses carry an `ETag`, and a `Last-Modified` for files on disk, so
+/// browsers can check whether their copy is still fresh, and parts of files
+/// can be requested with `Range`. Text files can be sent compressed, and are
+/// only compressed once per version.
+pub struct StaticFiles {
+    root: Option<PathBuf>,
+    sniff: bool,
+    compress_above: Option<usize>,
+    /// The ETags of the embedded files by name, worked out once up front.
+    embedded_etags: HashMap<&'static str, String>,
+    /// Compressed files by path and encoding.
+    compressed: Mutex<HashMap<(String, Encoding), Compressed>>,
+}
+
+impl StaticFiles {
+    pub fn new(root: Option<&Path>) -> io::Result<Self> {
+        Ok(Self {
+            root: root.map(Path::canonicalize).transpose()?,
+            sniff: false,
+            compress_above: None,
+            embedded_etags: assets::EMBEDDED
+                .iter()
+                .map(|(name, content)| (*name, embedded_etag(content)))
+                .collect(),
+            compressed: Mutex::new(HashMap::new()),
+        })
+    }
+
+    /// Whether files with an unknown extension have their type guessed from
+    /// their content instead of being served as arbitrary bytes.
+    pub fn sniff(mut self, sniff: bool) -> Self {
+        self.sniff = sniff;
+
+        self
+    }
+
+    /// Compresses text files of at least `min_size` bytes for clients that
+    /// accept it, keeping the result for the next client that asks.
+    pub fn compress(mut self, min_size: Option<usize>) -> Self {
+        self.compress_above = min_size;
+
+        self
+    }
+
+    pub fn root(&self) -> Option<&Path> {
+        self.root.as_deref()
+    }
+
+    /// Finds the file on disk the path points to, if it is a file inside the
+    /// root.
+    pub fn resolve(&self, path: &str) -> Option<PathBuf> {
+        let root = self.root.as_ref()?;
+
+        // Symbolic links could still lead outside
+        let resolved = root.join(relative_path(path)?).canonicalize().ok()?;
+
+        match resolved.starts_with(root) && resolved.is_file() {
+            true => Some(resolved),
+            false => None,
+        }
+    }
+
+    /// Looks the path up on disk, then among the embedded files.
+    fn load(&self, path: &str) -> io::Result<Option<Asset>> {
+        if let Some(file_path) = self.resolve(path) {
+            return Asset::read(&file_path).map(Some);
+        }
+
+        let Some(name) = relative_path(path).and_then(|p| p.to_str().map(str::to_string)) else {
+            return Ok(None);
+        };
+
+        let asset = assets::get(&name).map(|content| Asset {
+            content: Cow::Borrowed(content),
+            etag: self.embedded_etags[name.as_str()].clone(),
+            modified: None,
+        });
+
+        Ok(asset)
+    }
+
+    /// Returns the content of the file at the path, for files that are
+    /// filled in before they are served.
+    pub fn read(&self, path: &str) -> io::Result<Option<Cow<'static, [u8]>>> {
+        Ok(self.load(path)?.map(|asset| asset.content))
+    }
+
+    /// Responds with the file at the path, or the not found page.
+    pub fn serve(&self, request: &HttpRequest, path: &str) -> HttpResponse {
+        if is_page(path) {
+            return self.not_found();
+        }
+
+        match self.load(path) {
+            Ok(Some(asset)) => self.serve_asset(request, path, asset),
+            Ok(None) => self.not_found(),
+            Err(e) => {
+                eprintln!("Encountered error reading {path}: {e}");
+
+                self.not_found()
+            }
+        }
+    }
+
+    fn serve_asset(&self, request: &HttpRequest, path: &str, asset: Asset) -> HttpResponse {
+        let len = asset.content.len() as u64;
+
+        let last_modified = asset.modified.map(http_date);
+
+        let content_type = mime_type_for(path).unwrap_or_else(|| match self.sniff {
+            true => sniff_mime_type(&asset.content[..asset.content.len().min(SNIFF_LENGTH)]),
+            false => DEFAULT_MIME_TYPE,
+        });
+
+        let compressible = is_compressible(content_type)
+            && self
+                .compress_above
+                .is_some_and(|min_size| asset.content.len() >= min_size);
+
+        let encoding = match compressible {
+            true => request
+                .get_header("Accept-Encoding")
+                .and_then(Encoding::negotiate),
+            false => None,
+        };
+
+        // Worked out up front, as whether the file is sent compressed decides
+        // the tag a revalidated copy keeps too
+        let compressed =
+            encoding.and_then(|encoding| match self.compressed(path, &asset, encoding) {
+                Ok(body) => body.map(|body| (encoding, body)),
+                Err(e) => {
+                    eprintln!("Encountered error compressing {path}: {e}");
+
+                    None
+                }
+            });
+
+        let mut builder = HttpResponse::builder()
+            .http_version("HTTP/1.1")
+            .add_header("ETag", &asset.etag)
+            .add_header("Accept-Ranges", "bytes")
+            // Browsers should go by the type given rather than guess their own
+            .add_header("X-Content-Type-Options", "nosniff");
+
+        if let Some(last_modified) = last_modified.as_deref() {
+            builder = builder.add_header("Last-Modified", last_modified);
+        }
+
+        if compressible {
+            builder = builder.add_header("Vary", "Accept-Encoding");
+        }
+
+        if !Self::modified_since(request, &asset) {
+            // The same tag the full response would have had
+            if compressed.is_some() {
+                builder = builder.add_header("ETag", &compression::weaken_etag(&asset.etag));
+            }
+
+            return builder
+                .status_code(304)
+                .status_message("Not Modified")
+                .build();
+        }
+
+        // A range of an older version of the file would be stitched onto the
+        // wrong bytes
+        let range_applies = request.get_header("If-Range").is_none_or(|validator| {
+            validator == asset.etag || Some(validator) == last_modified.as_deref()
+        });
+
+        let range = match range_applies {
+            true => ByteRange::parse(request.get_header("Range"), len),
+            false => ByteRange::Full,
+        };
+
+        match range {
+            ByteRange::Full => {
+                let builder = builder
+                    .status_code(200)
+                    .status_message("OK")
+                    .add_header("Content-Type", content_type);
+
+                match compressed {
+                    Some((encoding, body)) => builder
+                        .add_header("Content-Encoding", encoding.name())
+                        .add_header("ETag", &compression::weaken_etag(&asset.etag))
+                        .bytes(body)
+                        .build(),
+                    None => builder.bytes(asset.content.into_owned()).build(),
+                }
+            }
+            // Ranges are always of the file as it is, never compressed
+            ByteRange::Partial(start, end) => builder
+                .status_code(206)
+                .status_message("Partial Content")
+                .add_header("Content-Type", content_type)
+                .add_header("Content-Range", &format!("bytes {start}-{end}/{len}"))
+                .bytes(asset.content[start as usize..=end as usize].to_vec())
+                .build(),
+            ByteRange::Unsatisfiable => builder
+                .status_code(416)
+                .status_message("Range Not Satisfiable")
+                .add_header("Content-Range", &format!("bytes */{len}"))
+                .build(),
+        }
+    }
+
+    /// Returns the file compressed in the encoding if that makes it smaller,
+    /// only compressing it again once it has changed.
+    fn compressed(
+        &self,
+        path: &str,
+        asset: &Asset,
+        encoding: Encoding,
+    ) -> io::Result<Option<Vec<u8>>> {
+        let key = (path.to_string(), encoding);
+
+        if let Some(compressed) = self.compressed.lock().unwrap().get(&key) {
+            if compressed.etag == asset.etag {
+                return Ok(compressed.body.clone());
+            }
+        }
+
+        let body = Some(encoding.compress(&asset.content)?)
+            .filter(|compressed| compressed.len() < asset.content.len());
+
+        self.compressed.lock().unwrap().insert(
+            key,
+            Compressed {
+                etag: asset.etag.clone(),
+                body: body.clone(),
+            },
+        );
+
+        Ok(body)
+    }
+
+    /// Whether the client's copy, if it has one, is out of date.
+    fn modified_since(request: &HttpRequest, asset: &Asset) -> bool {
+        // Weak and strong tags are the same thing when only checking freshness
+        if let Some(tags) = request.get_header("If-None-Match") {
+            return !tags
+                .split(',')
+                .map(|t| t.trim())
+                .any(|t| t == "*" || t.strip_prefix("W/").unwrap_or(t) == asset.etag);
+        }
+
+        let since = request
+            .get_header("If-Modified-Since")
+            .and_then(parse_http_date);
+
+        match (asset.modified, since) {
+            // HTTP dates have no fractions of a second
+            (Some(modified), Some(since)) => modified
+                .duration_since(since)
+                .is_ok_and(|newer| newer >= Duration::from_secs(1)),
+            _ => true,
+        }
+    }
+
+    /// Responds with the not found page.
+    pub fn not_found(&self) -> HttpResponse {
+        match self.read(NOT_FOUND_PAGE) {
+            Ok(Some(page)) => HttpResponse::builder()
+                .http_version("HTTP/1.1")
+                .status_code(404)
+                .status_message("Not Found")
+                .add_header("Content-Type", get_mime_type(NOT_FOUND_PAGE))
+                .bytes(page.into_owned())
+                .build(),
+            _ => HttpResponse::not_found(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::TempDir;
+    use std::fs;
+
+    /// Serves a few files from a root of the test's own.
+    fn static_files(dir: &TempDir) -> StaticFiles {
+        let path = dir.path();
+
+        fs::create_dir_all(path.join("css")).unwrap();
+        fs::write(path.join("css/chat.css"), "0123456789").unwrap();
+        fs::write(path.join(NOT_FOUND_PAGE), "<h1>Gone</h1>").unwrap();
+        fs::write(path.join("LICENSE"), "Do what you want").unwrap();
+
+        StaticFiles::new(Some(path)).unwrap()
+    }
+
+    fn get(headers: &str) -> HttpRequest {
+        HttpRequest::parse(&format!(
+            "GET /static/css/chat.css HTTP/1.1\r\n{headers}\r\n"
+        ))
+        .unwrap()
+    }
+
+    #[test]
+    fn test_http_dates() {
+        let time = UNIX_EPOCH + Duration::from_secs(784111777);
+
+        assert_eq!("Sun, 06 Nov 1994 08:49:37 GMT", http_date(time));
+        assert_eq!(Some(time), parse_http_date("Sun, 06 Nov 1994 08:49:37 GMT"));
+        assert_eq!("Thu, 01 Jan 1970 00:00:00 GMT", http_date(UNIX_EPOCH));
+        assert_eq!(
+            "Thu, 29 Feb 2024 23:59:59 GMT",
+            http_date(UNIX_EPOCH + Duration::from_secs(1709251199))
+        );
+
+        assert_eq!(None, parse_http_date("Sunday, 06-Nov-94 08:49:37 GMT"));
+        assert_eq!(None, parse_http_date("garbage"));
+    }
+
+    #[test]
+    fn test_parse_ranges() {
+        let parse = |range| ByteRange::parse(Some(range), 10);
+
+        assert_eq!(ByteRange::Full, ByteRange::parse(None, 10));
+        assert_eq!(ByteRange::Partial(0, 4), parse("bytes=0-4"));
+        assert_eq!(ByteRange::Partial(5, 9), parse("bytes=5-"));
+        assert_eq!(ByteRange::Partial(7, 9), parse("bytes=-3"));
+        assert_eq!(ByteRange::Partial(0, 9), parse("bytes=-30"));
+        assert_eq!(ByteRange::Partial(8, 9), parse("bytes=8-100"));
+        assert_eq!(ByteRange::Unsatisfiable, parse("bytes=10-"));
+        assert_eq!(ByteRange::Unsatisfiable, parse("bytes=-0"));
+        assert_eq!(ByteRange::Full, parse("bytes=4-2"));
+        assert_eq!(ByteRange::Full, parse("bytes=0-1, 4-5"));
+        assert_eq!(ByteRange::Full, parse("lines=0-1"));
+    }
+
+    #[test]
+    fn test_paths_stay_inside_root() {
+        let dir = TempDir::new("static");
+        let files = static_files(&dir);
+
+        assert!(files.resolve("css/chat.css").is_some());
+        assert!(files.resolve("./css/../css/chat.css").is_none());
+        assert!(files.resolve("../../etc/passwd").is_none());
+        assert!(files.resolve("/etc/passwd").is_none());
+        assert!(files.resolve("css").is_none());
+        assert!(files.resolve("missing.css").is_none());
+
+        let response = files.serve(&get(""), "../../etc/passwd");
+
+        assert_eq!(404, response.status_code);
+        assert_eq!(Some(&b"<h1>Gone</h1>"[..]), response.body());
+    }
+
+    #[test]
+    fn test_conditional_requests() {
+        let dir = TempDir::new("static");
+        let files = static_files(&dir);
+
+        let response = files.serve(&get(""), "css/chat.css");
+
+        assert_eq!(200, response.status_code);
+        assert_eq!(Some(&b"0123456789"[..]), response.body());
+
+        let etag = response.headers().get("ETag").unwrap().to_string();
+        let last_modified = response.headers().get("Last-Modified").unwrap().to_string();
+
+        let cached = |header: &str| {
+            files
+                .serve(&get(&format!("{header}\r\n")), "css/chat.css")
+                .status_code
+        };
+
+        assert_eq!(304, cached(&format!("If-None-Match: {etag}")));
+        assert_eq!(304, cached(&format!("If-None-Match: \"other\", W/{etag}")));
+        assert_eq!(200, cached("If-None-Match: \"other\""));
+        assert_eq!(304, cached(&format!("If-Modified-Since: {last_modified}")));
+        assert_eq!(
+            200,
+            cached("If-Modified-Since: Thu, 01 Jan 1970 00:00:00 GMT")
+        );
+    }
+
+    #[test]
+    fn test_content_types() {
+        let dir = TempDir::new("static");
+        let files = static_files(&dir);
+
+        let content_type = |files: &StaticFiles, path| {
+            files
+                .serve(&get(""), path)
+                .headers()
+                .get("Content-Type")
+                .map(str::to_string)
+        };
+
+        assert_eq!(
+            Some("text/css; charset=utf-8"),
+            content_type(&files, "css/chat.css").as_deref()
+        );
+        assert_eq!(
+            Some(DEFAULT_MIME_TYPE),
+            content_type(&files, "LICENSE").as_deref()
+        );
+
+        let sniffing = StaticFiles::new(files.root()).unwrap().sniff(true);
+
+        assert_eq!(
+            Some("text/plain; charset=utf-8"),
+            content_type(&sniffing, "LICENSE").as_deref()
+        );
+    }
+
+    #[test]
+    fn test_disk_shadows_embedded_files() {
+        let embedded = StaticFiles::new(None).unwrap();
+
+        let response = embedded.serve(&get(""), "./chat.js");
+
+        assert_eq!(200, response.status_code);
+        assert_eq!(assets::get("chat.js"), response.body());
+        assert!(response.headers().get("Last-Modified").is_none());
+
+        let etag = response.headers().get("ETag").unwrap().to_string();
+
+        assert_eq!(
+            304,
+            embedded
+                .serve(&get(&format!("If-None-Match: {etag}\r\n")), "chat.js")
+                .status_code
+        );
+        assert_eq!(404, embedded.serve(&get(""), "../src/main.rs").status_code);
+
+        let dir = TempDir::new("static");
+        let files = static_files(&dir);
+
+        fs::write(files.root().unwrap().join("chat.js"), "// Changed").unwrap();
+
+        assert_eq!(
+            Some(&b"// Changed"[..]),
+            files.serve(&get(""), "chat.js").body()
+        );
+        assert_eq!(
+            assets::get("lobby.js"),
+            files.serve(&get(""), "lobby.js").body()
+        );
+    }
+
+    #[test]
+    fn test_pages_are_not_served() {
+        let dir = TempDir::new("static");
+        let files = static_files(&dir);
+
+        fs::write(dir.path().join("footer.html"), "{{room}}").unwrap();
+
+        for page in ["index.html", "head.html", "./chat.HTML", "footer.html"] {
+            let response = files.serve(&get(""), page);
+
+            assert_eq!(404, response.status_code, "{page} was served");
+            assert_eq!(Some(&b"<h1>Gone</h1>"[..]), response.body());
+        }
+
+        // They can still be read to be filled in
+        assert_eq!(
+            assets::get("head.html"),
+            files.read("head.html").unwrap().as_deref()
+        );
+    }
+
+    #[test]
+    fn test_range_requests() {
+        let dir = TempDir::new("static");
+        let files = static_files(&dir);
+
+        let response = files.serve(&get("Range: bytes=2-5\r\n"), "css/chat.css");
+
+        assert_eq!(206, response.status_code);
+        assert_eq!(
+            Some("bytes 2-5/10"),
+            response.headers().get("Content-Range")
+        );
+        assert_eq!(Some(&b"2345"[..]), response.body());
+
+        let response = files.serve(&get("Range: bytes=20-\r\n"), "css/chat.css");
+
+        assert_eq!(416, response.status_code);
+        assert_eq!(Some("bytes */10"), response.headers().get("Content-Range"));
+
+        // The client's copy is of some other version of the file
+        let response = files.serve(
+            &get("Range: bytes=2-5\r\nIf-Range: \"stale\"\r\n"),
+            "css/chat.css",
+        );
+
+        assert_eq!(200, response.status_code);
+        assert_eq!(Some(&b"0123456789"[..]), response.body());
+    }
+
+    #[test]
+    fn test_compression() {
+        let files = StaticFiles::new(None).unwrap().compress(Some(1024));
+
+        let accept = "Accept-Encoding: gzip, deflate\r\n";
+
+        let response = files.serve(&get(accept), "chat.js");
+
+        assert_eq!(200, response.status_code);
+        assert_eq!(Some("gzip"), response.headers().get("Content-Encoding"));
+        assert_eq!(Some("Accept-Encoding"), response.headers().get("Vary"));
+
+        let etag = response.headers().get("ETag").unwrap().to_string();
+
+        assert!(etag.starts_with("W/"));
+
+        let mut decoded = Vec::new();
+
+        flate2::read::GzDecoder::new(response.body().unwrap())
+            .read_to_end(&mut decoded)
+            .unwrap();
+
+        assert_eq!(assets::get("chat.js"), Some(decoded.as_slice()));
+
+        // Later requests are answered from the cache
+        assert_eq!(response.body(), files.serve(&get(accept), "chat.js").body());
+        assert_eq!(1, files.compressed.lock().unwrap().len());
+
+        let response = files.serve(
+            &get(&format!("{accept}If-None-Match: {etag}\r\n")),
+            "chat.js",
+        );
+
+        assert_eq!(304, response.status_code);
+        assert_eq!(Some(etag.as_str()), response.headers().get("ETag"));
+
+        // Ranges are of the file as it is
+        let response = files.serve(&get(&format!("{accept}Range: bytes=0-9\r\n")), "chat.js");
+
+        assert_eq!(206, response.status_code);
+        assert_eq!(None, response.headers().get("Content-Encoding"));
+
+        let response = files.serve(&get(""), "chat.js");
+
+        assert_eq!(None, response.headers().get("Content-Encoding"));
+        assert_eq!(Some("Accept-Encoding"), response.headers().get("Vary"));
+        assert_eq!(assets::get("chat.js"), response.body());
+
+        // Nor are files too small to be worth it
+        let response = files.serve(&get(accept), "base.css");
+
+        assert_eq!(None, response.headers().get("Content-Encoding"));
+    }
+
+    #[test]
+    fn test_incompressible_files_are_sent_as_they_are() {
+        let dir = TempDir::new("static");
+        let files = static_files(&dir).compress(Some(16));
+
+        // Bytes with no pattern to them come out larger once compressed
+        let mut seed = 1u32;
+        let noise: Vec<u8> = (0..4096)
+            .map(|_| {
+                seed = seed.wrapping_mul(1_664_525).wrapping_add(1_013_904_223);
+
+                (seed >> 24) as u8
+            })
+            .collect();
+
+        fs::write(dir.path().join("noise.js"), &noise).unwrap();
+
+        for _ in 0..2 {
+            let response = files.serve(&get("Accept-Encoding: gzip\r\n"), "noise.js");
+
+            assert_eq!(200, response.status_code);
+            assert_eq!(None, response.headers().get("Content-Encoding"));
+            assert_eq!(Some("Accept-Encoding"), response.headers().get("Vary"));
+            assert!(!response.headers().get("ETag").unwrap().starts_with("W/"));
+            assert_eq!(Some(noise.as_slice()), response.body());
+        }
+
+        assert_eq!(1, files.compressed.lock().unwrap().len());
+    }
+}
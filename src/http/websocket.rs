@@ -0,0 +1,908 @@
+use std::io::{self, Read, Write};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+
+use sha1_smol::Sha1;
+
+use crate::error::{HandshakeError, WebSocketError};
+use crate::http::{HttpRequest, HttpResponse, HttpResponseBuilder};
+
+/// Appended to the client's key before hashing it, as specified by RFC 6455.
+const WEBSOCKET_GUID: &str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+
+/// Largest payload a control frame may carry.
+const MAX_CONTROL_PAYLOAD: usize = 125;
+
+const BASE64_ALPHABET: &[u8] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+fn base64_encode(input: &[u8]) -> String {
+    let mut encoded = String::with_capacity(input.len().div_ceil(3) * 4);
+
+    for chunk in input.chunks(3) {
+        let bytes = [
+            chunk[0],
+            *chunk.get(1).unwrap_or(&0),
+            *chunk.get(2).unwrap_or(&0),
+        ];
+        let triple = u32::from_be_bytes([0, bytes[0], bytes[1], bytes[2]]);
+
+        for i in 0..4 {
+            if i <= chunk.len() {
+                let index = (triple >> (18 - 6 * i)) & 0x3f;
+
+                encoded.push(BASE64_ALPHABET[index as usize] as char);
+            } else {
+                encoded.push('=');
+            }
+        }
+    }
+
+    encoded
+}
+
+/// Computes the `Sec-WebSocket-Accept` value for a `Sec-WebSocket-Key`.
+pub fn accept_key(key: &str) -> String {
+    let mut hasher = Sha1::new();
+
+    hasher.update(key.as_bytes());
+    hasher.update(WEBSOCKET_GUID.as_bytes());
+
+    base64_encode(&hasher.digest().bytes())
+}
+
+/// Checks that the request asks for a WebSocket upgrade and returns the
+/// `101 Switching Protocols` response accepting it, which can still be given
+/// an upgrade handler before it is built.
+pub fn handshake(request: &HttpRequest) -> Result<HttpResponseBuilder, HandshakeError> {
+    let has_token = |header: &str, token: &str| {
+        request
+            .headers()
+            .get_all(header)
+            .flat_map(|h| h.split(','))
+            .any(|h| h.trim().eq_ignore_ascii_case(token))
+    };
+
+    if request.method != "GET" {
+        return Err(HandshakeError::InvalidMethod);
+    }
+
+    if !has_token("Upgrade", "websocket") || !has_token("Connection", "upgrade") {
+        return Err(HandshakeError::MissingUpgrade);
+    }
+
+    if request.get_header("Sec-WebSocket-Version") != Some("13") {
+        return Err(HandshakeError::UnsupportedVersion);
+    }
+
+    let key = request
+        .get_header("Sec-WebSocket-Key")
+        .ok_or(HandshakeError::MissingKey)?;
+
+    // The key is 16 random bytes encoded as base64
+    let valid_key = key.len() == 24
+        && key.ends_with("==")
+        && key[..22].bytes().all(|b| BASE64_ALPHABET.contains(&b));
+
+    if !valid_key {
+        return Err(HandshakeError::InvalidKey(key.to_string()));
+    }
+
+    Ok(HttpResponse::builder()
+        .http_version("HTTP/1.1")
+        .status_code(101)
+        .status_message("Switching Protocols")
+        .add_header("Upgrade", "websocket")
+        .add_header("Connection", "Upgrade")
+        .add_header("Sec-WebSocket-Accept", &accept_key(key)))
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Opcode {
+    Continuation,
+    Text,
+    Binary,
+    Close,
+    Ping,
+    Pong,
+}
+
+impl Opcode {
+    fn from_u8(opcode: u8) -> Option<Self> {
+        match opcode {
+            0x0 => Some(Self::Continuation),
+            0x1 => Some(Self::Text),
+            0x2 => Some(Self::Binary),
+            0x8 => Some(Self::Close),
+            0x9 => Some(Self::Ping),
+            0xa => Some(Self::Pong),
+            _ => None,
+        }
+    }
+
+    fn as_u8(self) -> u8 {
+        match self {
+            Self::Continuation => 0x0,
+            Self::Text => 0x1,
+            Self::Binary => 0x2,
+            Self::Close => 0x8,
+            Self::Ping => 0x9,
+            Self::Pong => 0xa,
+        }
+    }
+
+    pub fn is_control(self) -> bool {
+        matches!(self, Self::Close | Self::Ping | Self::Pong)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Frame {
+    pub fin: bool,
+    pub opcode: Opcode,
+    pub payload: Vec<u8>,
+}
+
+impl Frame {
+    pub fn new(fin: bool, opcode: Opcode, payload: Vec<u8>) -> Self {
+        Self {
+            fin,
+            opcode,
+            payload,
+        }
+    }
+
+    pub fn text(text: &str) -> Self {
+        Self::new(true, Opcode::Text, text.as_bytes().to_vec())
+    }
+
+    pub fn binary(data: Vec<u8>) -> Self {
+        Self::new(true, Opcode::Binary, data)
+    }
+
+    pub fn ping(data: Vec<u8>) -> Self {
+        Self::new(true, Opcode::Ping, data)
+    }
+
+    pub fn pong(data: Vec<u8>) -> Self {
+        Self::new(true, Opcode::Pong, data)
+    }
+
+    pub fn close(close: Option<&CloseFrame>) -> Self {
+        let payload = match close {
+            Some(close) => {
+                let mut payload = close.code.to_be_bytes().to_vec();
+
+                // Cutting a character in half would make the reason invalid
+                let mut end = close.reason.len().min(MAX_CONTROL_PAYLOAD - payload.len());
+
+                while !close.reason.is_char_boundary(end) {
+                    end -= 1;
+                }
+
+                payload.extend_from_slice(&close.reason.as_bytes()[..end]);
+
+                payload
+            }
+            None => Vec::new(),
+        };
+
+        Self::new(true, Opcode::Close, payload)
+    }
+
+    /// Reads a frame, unmasking its payload. Frames sent by clients must be
+    /// masked, so a server passes `require_mask` to reject those that are
+    /// not. `max_payload` limits data frames only, as control frames always
+    /// fit in 125 bytes.
+    pub fn read_from<R: Read + ?Sized>(
+        reader: &mut R,
+        max_payload: usize,
+        require_mask: bool,
+    ) -> Result<Self, WebSocketError> {
+        let mut header = [0; 2];
+
+        reader.read_exact(&mut header).map_err(|e| match e.kind() {
+            io::ErrorKind::UnexpectedEof => WebSocketError::ConnectionClosed,
+            _ => WebSocketError::Io(e),
+        })?;
+
+        let fin = header[0] & 0x80 != 0;
+
+        if header[0] & 0x70 != 0 {
+            return Err(WebSocketError::Protocol("Reserved bits are set"));
+        }
+
+        let opcode =
+            Opcode::from_u8(header[0] & 0x0f).ok_or(WebSocketError::Protocol("Unknown opcode"))?;
+
+        let masked = header[1] & 0x80 != 0;
+
+        if require_mask && !masked {
+            return Err(WebSocketError::Protocol("Client frames must be masked"));
+        }
+
+        let len = match header[1] & 0x7f {
+            126 => {
+                let mut len = [0; 2];
+                reader.read_exact(&mut len)?;
+
+                u16::from_be_bytes(len) as u64
+            }
+            127 => {
+                let mut len = [0; 8];
+                reader.read_exact(&mut len)?;
+
+                let len = u64::from_be_bytes(len);
+
+                if len & (1 << 63) != 0 {
+                    return Err(WebSocketError::Protocol("Payload length is out of range"));
+                }
+
+                len
+            }
+            len => len as u64,
+        };
+
+        if opcode.is_control() {
+            if !fin {
+                return Err(WebSocketError::Protocol(
+                    "Control frames cannot be fragmented",
+                ));
+            }
+
+            if len > MAX_CONTROL_PAYLOAD as u64 {
+                return Err(WebSocketError::Protocol(
+                    "Control frame payload is too long",
+                ));
+            }
+        } else if len > max_payload as u64 {
+            return Err(WebSocketError::MessageTooLarge);
+        }
+
+        let mut mask = [0; 4];
+
+        if masked {
+            reader.read_exact(&mut mask)?;
+        }
+
+        let mut payload = vec![0; len as usize];
+        reader.read_exact(&mut payload)?;
+
+        if masked {
+            apply_mask(&mut payload, mask);
+        }
+
+        Ok(Self::new(fin, opcode, payload))
+    }
+
+    /// Writes the frame, masking its payload with the key if one is given as
+    /// clients are required to.
+    pub fn write_to<W: Write + ?Sized>(
+        &self,
+        writer: &mut W,
+        mask: Option<[u8; 4]>,
+    ) -> io::Result<()> {
+        let mut frame = Vec::with_capacity(self.payload.len() + 14);
+
+        frame.push(((self.fin as u8) << 7) | self.opcode.as_u8());
+
+        let mask_bit = (mask.is_some() as u8) << 7;
+
+        match self.payload.len() {
+            len @ 0..=125 => frame.push(mask_bit | len as u8),
+            len @ 126..=0xffff => {
+                frame.push(mask_bit | 126);
+                frame.extend_from_slice(&(len as u16).to_be_bytes());
+            }
+            len => {
+                frame.push(mask_bit | 127);
+                frame.extend_from_slice(&(len as u64).to_be_bytes());
+            }
+        }
+
+        let payload_start = match mask {
+            Some(mask) => {
+                frame.extend_from_slice(&mask);
+                frame.len()
+            }
+            None => frame.len(),
+        };
+
+        frame.extend_from_slice(&self.payload);
+
+        if let Some(mask) = mask {
+            apply_mask(&mut frame[payload_start..], mask);
+        }
+
+        writer.write_all(&frame)?;
+        writer.flush()
+    }
+}
+
+fn apply_mask(payload: &mut [u8], mask: [u8; 4]) {
+    for (i, byte) in payload.iter_mut().enumerate() {
+        *byte ^= mask[i % 4];
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CloseFrame {
+    pub code: u16,
+    pub reason: String,
+}
+
+impl CloseFrame {
+    pub fn new(code: u16, reason: &str) -> Self {
+        Self {
+            code,
+            reason: reason.to_string(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Message {
+    Text(String),
+    Binary(Vec<u8>),
+    Ping(Vec<u8>),
+    Pong(Vec<u8>),
+    Close(Option<CloseFrame>),
+}
+
+/// Reads whole messages off of a stream, putting fragmented messages back
+/// together. Control frames interleaved with the fragments are returned as
+/// they arrive.
+pub struct MessageReader<R> {
+    reader: R,
+    max_message_size: usize,
+    require_mask: bool,
+    fragments: Option<(Opcode, Vec<u8>)>,
+}
+
+impl<R: Read> MessageReader<R> {
+    pub fn new(reader: R, max_message_size: usize, require_mask: bool) -> Self {
+        Self {
+            reader,
+            max_message_size,
+            require_mask,
+            fragments: None,
+        }
+    }
+
+    pub fn read_message(&mut self) -> Result<Message, WebSocketError> {
+        loop {
+            let buffered = self.fragments.as_ref().map_or(0, |(_, f)| f.len());
+
+            let frame = Frame::read_from(
+                &mut self.reader,
+                self.max_message_size - buffered,
+                self.require_mask,
+            )?;
+
+            let (opcode, payload) = match (frame.opcode, self.fragments.take()) {
+                (Opcode::Close, fragments) => {
+                    self.fragments = fragments;
+
+                    return Self::close_message(frame.payload);
+                }
+                (Opcode::Ping, fragments) => {
+                    self.fragments = fragments;
+
+                    return Ok(Message::Ping(frame.payload));
+                }
+                (Opcode::Pong, fragments) => {
+                    self.fragments = fragments;
+
+                    return Ok(Message::Pong(frame.payload));
+                }
+                (Opcode::Continuation, Some((opcode, mut payload))) => {
+                    payload.extend_from_slice(&frame.payload);
+
+                    (opcode, payload)
+                }
+                (Opcode::Continuation, None) => {
+                    return Err(WebSocketError::Protocol(
+                        "Continuation frame without a message",
+                    ))
+                }
+                (_, Some(_)) => {
+                    return Err(WebSocketError::Protocol(
+                        "New message started before the last one finished",
+                    ))
+                }
+                (opcode, None) => (opcode, frame.payload),
+            };
+
+            if !frame.fin {
+                self.fragments = Some((opcode, payload));
+
+                continue;
+            }
+
+            return match opcode {
+                Opcode::Text => String::from_utf8(payload)
+                    .map(Message::Text)
+                    .map_err(|_| WebSocketError::InvalidUtf8),
+                _ => Ok(Message::Binary(payload)),
+            };
+        }
+    }
+
+    fn close_message(payload: Vec<u8>) -> Result<Message, WebSocketError> {
+        match payload.len() {
+            0 => Ok(Message::Close(None)),
+            1 => Err(WebSocketError::Protocol("Close frame payload is too short")),
+            _ => {
+                let code = u16::from_be_bytes([payload[0], payload[1]]);
+                let reason = String::from_utf8(payload[2..].to_vec())
+                    .map_err(|_| WebSocketError::InvalidUtf8)?;
+
+                Ok(Message::Close(Some(CloseFrame { code, reason })))
+            }
+        }
+    }
+}
+
+/// The sending half of a WebSocket connection, which can be shared with
+/// threads that send while another one reads.
+pub struct WebSocketSender<W> {
+    writer: Mutex<W>,
+    closing: AtomicBool,
+}
+
+impl<W: Write> WebSocketSender<W> {
+    pub fn send(&self, frame: &Frame) -> io::Result<()> {
+        frame.write_to(&mut *self.writer.lock().unwrap(), None)
+    }
+
+    pub fn send_text(&self, text: &str) -> io::Result<()> {
+        self.send(&Frame::text(text))
+    }
+
+    pub fn send_binary(&self, data: Vec<u8>) -> io::Result<()> {
+        self.send(&Frame::binary(data))
+    }
+
+    pub fn ping(&self, data: Vec<u8>) -> io::Result<()> {
+        self.send(&Frame::ping(data))
+    }
+
+    /// Starts the closing handshake, unless either side already has. The
+    /// client's close frame is still returned by `read_message`.
+    pub fn close(&self, code: u16, reason: &str) -> io::Result<()> {
+        self.send_close(Some(&CloseFrame::new(code, reason)))
+    }
+
+    /// Sends the close frame if no close frame was sent yet.
+    fn send_close(&self, close: Option<&CloseFrame>) -> io::Result<()> {
+        match self.closing.swap(true, Ordering::SeqCst) {
+            true => Ok(()),
+            false => self.send(&Frame::close(close)),
+        }
+    }
+
+    pub fn is_closing(&self) -> bool {
+        self.closing.load(Ordering::SeqCst)
+    }
+}
+
+/// The server's end of a WebSocket connection. Pings are answered and closes
+/// are echoed while reading, so callers only need to handle data messages and
+/// the final close.
+pub struct WebSocket<R, W> {
+    messages: MessageReader<R>,
+    sender: Arc<WebSocketSender<W>>,
+}
+
+impl<R: Read, W: Write> WebSocket<R, W> {
+    pub fn new(reader: R, writer: W, max_message_size: usize) -> Self {
+        Self {
+            messages: MessageReader::new(reader, max_message_size, true),
+            sender: Arc::new(WebSocketSender {
+                writer: Mutex::new(writer),
+                closing: AtomicBool::new(false),
+            }),
+        }
+    }
+
+    /// Returns the sending half, for sending from another thread while this
+    /// one reads.
+    pub fn sender(&self) -> Arc<WebSocketSender<W>> {
+        Arc::clone(&self.sender)
+    }
+
+    pub fn read_message(&mut self) -> Result<Message, WebSocketError> {
+        loop {
+            match self.messages.read_message() {
+                Ok(Message::Ping(data)) => self.sender.send(&Frame::pong(data))?,
+                Ok(Message::Pong(_)) => (),
+                Ok(Message::Close(close)) => {
+                    // Unless this answers the server's own close frame
+                    self.sender.send_close(close.as_ref())?;
+
+                    return Ok(Message::Close(close));
+                }
+                Ok(message) => return Ok(message),
+                Err(e) => {
+                    // Let the client know why, if the connection is still usable
+                    if let Some(code) = e.close_code() {
+                        let _ = self.sender.close(code, &e.to_string());
+                    }
+
+                    return Err(e);
+                }
+            }
+        }
+    }
+
+    pub fn send(&self, frame: &Frame) -> io::Result<()> {
+        self.sender.send(frame)
+    }
+
+    pub fn send_text(&self, text: &str) -> io::Result<()> {
+        self.sender.send_text(text)
+    }
+
+    pub fn send_binary(&self, data: Vec<u8>) -> io::Result<()> {
+        self.sender.send_binary(data)
+    }
+
+    pub fn ping(&self, data: Vec<u8>) -> io::Result<()> {
+        self.sender.ping(data)
+    }
+
+    pub fn close(&self, code: u16, reason: &str) -> io::Result<()> {
+        self.sender.close(code, reason)
+    }
+
+    /// Returns the reader and writer, once no other thread holds on to the
+    /// sending half.
+    pub fn into_parts(self) -> Option<(R, W)> {
+        let sender = Arc::into_inner(self.sender)?;
+
+        Some((self.messages.reader, sender.writer.into_inner().unwrap()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    const MASK: [u8; 4] = [0x37, 0xfa, 0x21, 0x3d];
+
+    /// Encodes frames the way a browser would send them.
+    fn client_frames(frames: &[Frame]) -> Cursor<Vec<u8>> {
+        let mut bytes = Vec::new();
+
+        for frame in frames {
+            frame.write_to(&mut bytes, Some(MASK)).unwrap();
+        }
+
+        Cursor::new(bytes)
+    }
+
+    fn server_frames(bytes: &[u8]) -> Vec<Frame> {
+        let mut reader = Cursor::new(bytes);
+        let mut frames = Vec::new();
+
+        while (reader.position() as usize) < bytes.len() {
+            frames.push(Frame::read_from(&mut reader, usize::MAX, false).unwrap());
+        }
+
+        frames
+    }
+
+    #[test]
+    fn test_accept_key() {
+        assert_eq!(
+            "s3pPLMBiTxaQ9kYGzzhZRbK+xOo=",
+            accept_key("dGhlIHNhbXBsZSBub25jZQ==")
+        );
+    }
+
+    #[test]
+    fn test_base64_encode() {
+        assert_eq!("", base64_encode(b""));
+        assert_eq!("Zg==", base64_encode(b"f"));
+        assert_eq!("Zm8=", base64_encode(b"fo"));
+        assert_eq!("Zm9v", base64_encode(b"foo"));
+        assert_eq!("Zm9vYmFy", base64_encode(b"foobar"));
+    }
+
+    #[test]
+    fn test_handshake() {
+        let request = HttpRequest::parse(concat!(
+            "GET /ws HTTP/1.1\r\n",
+            "Host: 192.168.4.28:1234\r\n",
+            "Upgrade: websocket\r\n",
+            "Connection: keep-alive, Upgrade\r\n",
+            "Sec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\n",
+            "Sec-WebSocket-Version: 13\r\n",
+            "\r\n"
+        ))
+        .unwrap();
+
+        let control = "HTTP/1.1 101 Switching Protocols\r\nUpgrade: websocket\r\nConnection: Upgrade\r\nSec-WebSocket-Accept: s3pPLMBiTxaQ9kYGzzhZRbK+xOo=\r\n\r\n";
+
+        assert_eq!(control, handshake(&request).unwrap().build().to_string());
+    }
+
+    #[test]
+    fn test_handshake_rejected() {
+        let handshake_error = |headers: &str| {
+            let raw = format!("GET /ws HTTP/1.1\r\n{headers}\r\n");
+
+            handshake(&HttpRequest::parse(&raw).unwrap()).unwrap_err()
+        };
+
+        assert_eq!(
+            HandshakeError::MissingUpgrade,
+            handshake_error(
+                "Sec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\nSec-WebSocket-Version: 13\r\n"
+            )
+        );
+        assert_eq!(
+            HandshakeError::UnsupportedVersion,
+            handshake_error("Upgrade: websocket\r\nConnection: Upgrade\r\nSec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\nSec-WebSocket-Version: 8\r\n")
+        );
+        assert_eq!(
+            HandshakeError::MissingKey,
+            handshake_error(
+                "Upgrade: websocket\r\nConnection: Upgrade\r\nSec-WebSocket-Version: 13\r\n"
+            )
+        );
+        assert_eq!(
+            HandshakeError::InvalidKey("short".to_string()),
+            handshake_error("Upgrade: websocket\r\nConnection: Upgrade\r\nSec-WebSocket-Key: short\r\nSec-WebSocket-Version: 13\r\n")
+        );
+    }
+
+    #[test]
+    fn test_frame_encoding() {
+        let mut bytes = Vec::new();
+
+        Frame::text("Hello").write_to(&mut bytes, None).unwrap();
+
+        assert_eq!(vec![0x81, 0x05, b'H', b'e', b'l', b'l', b'o'], bytes);
+
+        // Masked example from RFC 6455 section 5.7
+        let mut bytes = Vec::new();
+
+        Frame::text("Hello")
+            .write_to(&mut bytes, Some(MASK))
+            .unwrap();
+
+        assert_eq!(
+            vec![0x81, 0x85, 0x37, 0xfa, 0x21, 0x3d, 0x7f, 0x9f, 0x4d, 0x51, 0x58],
+            bytes
+        );
+    }
+
+    #[test]
+    fn test_frame_extended_lengths() {
+        for len in [125, 126, 0xffff, 0x10000] {
+            let frame = Frame::binary(vec![7; len]);
+
+            let mut bytes = Vec::new();
+            frame.write_to(&mut bytes, Some(MASK)).unwrap();
+
+            let header_len = match len {
+                0..=125 => 2,
+                126..=0xffff => 4,
+                _ => 10,
+            };
+
+            assert_eq!(header_len + 4 + len, bytes.len());
+
+            let read = Frame::read_from(&mut Cursor::new(bytes), usize::MAX, true).unwrap();
+
+            assert_eq!(frame, read);
+        }
+    }
+
+    #[test]
+    fn test_frame_rejects_unmasked_client_frame() {
+        let mut bytes = Vec::new();
+
+        Frame::text("Hello").write_to(&mut bytes, None).unwrap();
+
+        assert!(matches!(
+            Frame::read_from(&mut Cursor::new(bytes), usize::MAX, true),
+            Err(WebSocketError::Protocol(_))
+        ));
+    }
+
+    #[test]
+    fn test_frame_rejects_invalid_control_frames() {
+        let fragmented_ping = client_frames(&[Frame::new(false, Opcode::Ping, vec![])]);
+        let long_ping = client_frames(&[Frame::ping(vec![0; 126])]);
+
+        for mut bytes in [fragmented_ping, long_ping] {
+            assert!(matches!(
+                Frame::read_from(&mut bytes, usize::MAX, true),
+                Err(WebSocketError::Protocol(_))
+            ));
+        }
+    }
+
+    #[test]
+    fn test_frame_reserved_bits_and_opcodes() {
+        for first_byte in [0xc1, 0x83] {
+            let mut bytes = Cursor::new(vec![first_byte, 0x80, 0, 0, 0, 0]);
+
+            assert!(matches!(
+                Frame::read_from(&mut bytes, usize::MAX, true),
+                Err(WebSocketError::Protocol(_))
+            ));
+        }
+    }
+
+    #[test]
+    fn test_read_fragmented_message_with_interleaved_ping() {
+        let reader = client_frames(&[
+            Frame::new(false, Opcode::Text, b"Hel".to_vec()),
+            Frame::ping(b"are you there".to_vec()),
+            Frame::new(false, Opcode::Continuation, b"lo, ".to_vec()),
+            Frame::new(true, Opcode::Continuation, "María".as_bytes().to_vec()),
+        ]);
+
+        let mut socket = WebSocket::new(reader, Vec::new(), 1024);
+
+        assert_eq!(
+            Message::Text("Hello, María".to_string()),
+            socket.read_message().unwrap()
+        );
+
+        let (_, written) = socket.into_parts().unwrap();
+
+        assert_eq!(
+            vec![Frame::pong(b"are you there".to_vec())],
+            server_frames(&written)
+        );
+    }
+
+    #[test]
+    fn test_read_binary_message() {
+        let reader = client_frames(&[Frame::binary(vec![0, 159, 146, 150])]);
+
+        let mut socket = WebSocket::new(reader, Vec::new(), 1024);
+
+        assert_eq!(
+            Message::Binary(vec![0, 159, 146, 150]),
+            socket.read_message().unwrap()
+        );
+    }
+
+    #[test]
+    fn test_read_fragmentation_errors() {
+        let continuation_first =
+            client_frames(&[Frame::new(true, Opcode::Continuation, b"lo".to_vec())]);
+
+        let interrupted = client_frames(&[
+            Frame::new(false, Opcode::Text, b"Hel".to_vec()),
+            Frame::text("lo"),
+        ]);
+
+        for reader in [continuation_first, interrupted] {
+            let mut messages = MessageReader::new(reader, 1024, true);
+
+            assert!(matches!(
+                messages.read_message(),
+                Err(WebSocketError::Protocol(_))
+            ));
+        }
+    }
+
+    #[test]
+    fn test_ping_between_fragments_of_a_large_message() {
+        let reader = client_frames(&[
+            Frame::new(false, Opcode::Binary, vec![0; 10]),
+            Frame::new(true, Opcode::Ping, vec![1; 4]),
+            Frame::new(true, Opcode::Continuation, vec![0; 2]),
+        ]);
+
+        let mut messages = MessageReader::new(reader, 12, true);
+
+        // The ping is larger than what is left of the message's budget
+        assert_eq!(Message::Ping(vec![1; 4]), messages.read_message().unwrap());
+        assert_eq!(
+            Message::Binary(vec![0; 12]),
+            messages.read_message().unwrap()
+        );
+    }
+
+    #[test]
+    fn test_read_message_too_large() {
+        let reader = client_frames(&[
+            Frame::new(false, Opcode::Binary, vec![0; 8]),
+            Frame::new(true, Opcode::Continuation, vec![0; 8]),
+        ]);
+
+        let mut socket = WebSocket::new(reader, Vec::new(), 12);
+
+        assert!(matches!(
+            socket.read_message(),
+            Err(WebSocketError::MessageTooLarge)
+        ));
+
+        let (_, written) = socket.into_parts().unwrap();
+
+        assert_eq!(Opcode::Close, server_frames(&written)[0].opcode);
+        assert_eq!([0x03, 0xf1], server_frames(&written)[0].payload[..2]);
+    }
+
+    #[test]
+    fn test_read_invalid_utf8() {
+        let reader = client_frames(&[Frame::new(true, Opcode::Text, vec![0xc3, 0x28])]);
+
+        let mut messages = MessageReader::new(reader, 1024, true);
+
+        assert!(matches!(
+            messages.read_message(),
+            Err(WebSocketError::InvalidUtf8)
+        ));
+    }
+
+    #[test]
+    fn test_close_is_echoed() {
+        let close = CloseFrame::new(1001, "going away");
+        let reader = client_frames(&[Frame::close(Some(&close))]);
+
+        let mut socket = WebSocket::new(reader, Vec::new(), 1024);
+
+        assert_eq!(
+            Message::Close(Some(close.clone())),
+            socket.read_message().unwrap()
+        );
+
+        let (_, written) = socket.into_parts().unwrap();
+
+        assert_eq!(vec![Frame::close(Some(&close))], server_frames(&written));
+    }
+
+    #[test]
+    fn test_server_initiated_close() {
+        let reader = client_frames(&[Frame::close(None)]);
+
+        let mut socket = WebSocket::new(reader, Vec::new(), 1024);
+
+        // Closing from another thread works the same
+        socket.sender().close(1000, "bye").unwrap();
+
+        assert_eq!(Message::Close(None), socket.read_message().unwrap());
+
+        let (_, written) = socket.into_parts().unwrap();
+
+        // The client's close only completes the handshake, it is not echoed
+        assert_eq!(
+            vec![Frame::close(Some(&CloseFrame::new(1000, "bye")))],
+            server_frames(&written)
+        );
+    }
+
+    #[test]
+    fn test_close_reason_is_truncated_between_characters() {
+        let frame = Frame::close(Some(&CloseFrame::new(1000, &"é".repeat(100))));
+
+        // 61 two-byte characters fit in the 123 bytes left after the code
+        assert_eq!(2 + 122, frame.payload.len());
+        assert_eq!(
+            "é".repeat(61),
+            std::str::from_utf8(&frame.payload[2..]).unwrap()
+        );
+
+        let frame = Frame::close(Some(&CloseFrame::new(1000, &"a".repeat(200))));
+
+        assert_eq!(MAX_CONTROL_PAYLOAD, frame.payload.len());
+    }
+
+    #[test]
+    fn test_read_closed_connection() {
+        let mut messages = MessageReader::new(Cursor::new(Vec::new()), 1024, true);
+
+        assert!(matches!(
+            messages.read_message(),
+            Err(WebSocketError::ConnectionClosed)
+        ));
+    }
+}
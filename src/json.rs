@@ -0,0 +1,523 @@
+use std::fmt::{self, Display, Write};
+
+use crate::error::JsonError;
+
+/// How deeply arrays and objects may nest before parsing gives up, so a
+/// hostile document cannot overflow the stack.
+const MAX_DEPTH: usize = 128;
+
+/// A JSON document. Object fields keep the order they were added in.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Json {
+    Null,
+    Bool(bool),
+    Number(f64),
+    String(String),
+    Array(Vec<Json>),
+    Object(Vec<(String, Json)>),
+}
+
+impl Json {
+    pub fn object<const N: usize>(fields: [(&str, Json); N]) -> Self {
+        Self::Object(
+            fields
+                .into_iter()
+                .map(|(k, v)| (k.to_string(), v))
+                .collect(),
+        )
+    }
+
+    pub fn parse(input: &str) -> Result<Self, JsonError> {
+        let mut parser = Parser { input, pos: 0 };
+
+        let value = parser.parse_value(0)?;
+
+        parser.skip_whitespace();
+
+        match parser.peek() {
+            Some(_) => Err(JsonError::TrailingCharacters(parser.pos)),
+            None => Ok(value),
+        }
+    }
+
+    /// Looks up a field of an object, returning `None` for anything else.
+    pub fn get(&self, key: &str) -> Option<&Json> {
+        match self {
+            Self::Object(fields) => fields.iter().find(|(k, _)| k == key).map(|(_, v)| v),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(&self) -> Option<&str> {
+        match self {
+            Self::String(s) => Some(s),
+            _ => None,
+        }
+    }
+
+    pub fn as_f64(&self) -> Option<f64> {
+        match self {
+            Self::Number(n) => Some(*n),
+            _ => None,
+        }
+    }
+
+    /// Returns the number if it is a non-negative integer.
+    pub fn as_u64(&self) -> Option<u64> {
+        self.as_f64()
+            .filter(|n| n.fract() == 0.0 && *n >= 0.0 && *n <= u64::MAX as f64)
+            .map(|n| n as u64)
+    }
+
+    pub fn as_bool(&self) -> Option<bool> {
+        match self {
+            Self::Bool(b) => Some(*b),
+            _ => None,
+        }
+    }
+
+    pub fn as_array(&self) -> Option<&[Json]> {
+        match self {
+            Self::Array(values) => Some(values),
+            _ => None,
+        }
+    }
+}
+
+impl From<&str> for Json {
+    fn from(s: &str) -> Self {
+        Self::String(s.to_string())
+    }
+}
+
+impl From<String> for Json {
+    fn from(s: String) -> Self {
+        Self::String(s)
+    }
+}
+
+impl From<u64> for Json {
+    fn from(n: u64) -> Self {
+        Self::Number(n as f64)
+    }
+}
+
+impl From<usize> for Json {
+    fn from(n: usize) -> Self {
+        Self::Number(n as f64)
+    }
+}
+
+impl From<bool> for Json {
+    fn from(b: bool) -> Self {
+        Self::Bool(b)
+    }
+}
+
+impl From<Vec<Json>> for Json {
+    fn from(values: Vec<Json>) -> Self {
+        Self::Array(values)
+    }
+}
+
+fn write_string(f: &mut fmt::Formatter<'_>, s: &str) -> fmt::Result {
+    f.write_char('"')?;
+
+    for c in s.chars() {
+        match c {
+            '"' => f.write_str("\\\"")?,
+            '\\' => f.write_str("\\\\")?,
+            '\n' => f.write_str("\\n")?,
+            '\r' => f.write_str("\\r")?,
+            '\t' => f.write_str("\\t")?,
+            '\u{8}' => f.write_str("\\b")?,
+            '\u{c}' => f.write_str("\\f")?,
+            c if c < ' ' => write!(f, "\\u{:04x}", c as u32)?,
+            c => f.write_char(c)?,
+        }
+    }
+
+    f.write_char('"')
+}
+
+// Serializes the document compactly, with a space after separators to match
+// what the server has always sent.
+impl Display for Json {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Null => f.write_str("null"),
+            Self::Bool(b) => write!(f, "{b}"),
+            // JSON has no representation for infinities or NaN
+            Self::Number(n) if !n.is_finite() => f.write_str("null"),
+            Self::Number(n) => write!(f, "{n}"),
+            Self::String(s) => write_string(f, s),
+            Self::Array(values) => {
+                f.write_char('[')?;
+
+                for (i, value) in values.iter().enumerate() {
+                    if i > 0 {
+                        f.write_str(", ")?;
+                    }
+
+                    write!(f, "{value}")?;
+                }
+
+                f.write_char(']')
+            }
+            Self::Object(fields) => {
+                f.write_char('{')?;
+
+                for (i, (key, value)) in fields.iter().enumerate() {
+                    if i > 0 {
+                        f.write_str(", ")?;
+                    }
+
+                    write_string(f, key)?;
+                    write!(f, ": {value}")?;
+                }
+
+                f.write_char('}')
+            }
+        }
+    }
+}
+
+struct Parser<'a> {
+    input: &'a str,
+    pos: usize,
+}
+
+impl Parser<'_> {
+    fn peek(&self) -> Option<char> {
+        self.input[self.pos..].chars().next()
+    }
+
+    fn next(&mut self) -> Option<char> {
+        let c = self.peek()?;
+
+        self.pos += c.len_utf8();
+
+        Some(c)
+    }
+
+    fn expect(&mut self, expected: char) -> Result<(), JsonError> {
+        match self.next() {
+            Some(c) if c == expected => Ok(()),
+            Some(c) => Err(JsonError::UnexpectedCharacter(c, self.pos - c.len_utf8())),
+            None => Err(JsonError::UnexpectedEnd),
+        }
+    }
+
+    fn skip_whitespace(&mut self) {
+        while matches!(self.peek(), Some(' ' | '\t' | '\n' | '\r')) {
+            self.pos += 1;
+        }
+    }
+
+    fn parse_value(&mut self, depth: usize) -> Result<Json, JsonError> {
+        if depth > MAX_DEPTH {
+            return Err(JsonError::TooDeep);
+        }
+
+        self.skip_whitespace();
+
+        match self.peek() {
+            Some('n') => self.parse_literal("null", Json::Null),
+            Some('t') => self.parse_literal("true", Json::Bool(true)),
+            Some('f') => self.parse_literal("false", Json::Bool(false)),
+            Some('"') => self.parse_string().map(Json::String),
+            Some('[') => self.parse_array(depth),
+            Some('{') => self.parse_object(depth),
+            Some('-' | '0'..='9') => self.parse_number(),
+            Some(c) => Err(JsonError::UnexpectedCharacter(c, self.pos)),
+            None => Err(JsonError::UnexpectedEnd),
+        }
+    }
+
+    fn parse_literal(&mut self, literal: &str, value: Json) -> Result<Json, JsonError> {
+        for expected in literal.chars() {
+            self.expect(expected)?;
+        }
+
+        Ok(value)
+    }
+
+    fn parse_number(&mut self) -> Result<Json, JsonError> {
+        let start = self.pos;
+
+        let digits = |parser: &mut Self| {
+            let from = parser.pos;
+
+            while matches!(parser.peek(), Some('0'..='9')) {
+                parser.pos += 1;
+            }
+
+            parser.pos > from
+        };
+
+        if self.peek() == Some('-') {
+            self.pos += 1;
+        }
+
+        // Leading zeros are not allowed
+        let valid = match self.peek() {
+            Some('0') => {
+                self.pos += 1;
+                true
+            }
+            _ => digits(self),
+        };
+
+        let valid = valid
+            && match self.peek() {
+                Some('.') => {
+                    self.pos += 1;
+                    digits(self)
+                }
+                _ => true,
+            }
+            && match self.peek() {
+                Some('e' | 'E') => {
+                    self.pos += 1;
+
+                    if matches!(self.peek(), Some('+' | '-')) {
+                        self.pos += 1;
+                    }
+
+                    digits(self)
+                }
+                _ => true,
+            };
+
+        let number = &self.input[start..self.pos];
+
+        match number.parse() {
+            Ok(n) if valid => Ok(Json::Number(n)),
+            _ => Err(JsonError::InvalidNumber(number.to_string())),
+        }
+    }
+
+    fn parse_hex_escape(&mut self) -> Result<u32, JsonError> {
+        let digits = self
+            .input
+            .get(self.pos..self.pos + 4)
+            .ok_or(JsonError::UnexpectedEnd)?;
+
+        let code = u32::from_str_radix(digits, 16)
+            .ok()
+            .filter(|_| digits.bytes().all(|b| b.is_ascii_hexdigit()))
+            .ok_or(JsonError::InvalidEscape(self.pos))?;
+
+        self.pos += 4;
+
+        Ok(code)
+    }
+
+    fn parse_string(&mut self) -> Result<String, JsonError> {
+        self.expect('"')?;
+
+        let mut s = String::new();
+
+        loop {
+            match self.next().ok_or(JsonError::UnexpectedEnd)? {
+                '"' => return Ok(s),
+                '\\' => {
+                    let escape_start = self.pos - 1;
+
+                    let c = match self.next().ok_or(JsonError::UnexpectedEnd)? {
+                        '"' => '"',
+                        '\\' => '\\',
+                        '/' => '/',
+                        'b' => '\u{8}',
+                        'f' => '\u{c}',
+                        'n' => '\n',
+                        'r' => '\r',
+                        't' => '\t',
+                        'u' => {
+                            let mut code = self.parse_hex_escape()?;
+
+                            // Characters outside the BMP are escaped as a
+                            // surrogate pair
+                            if (0xd800..0xdc00).contains(&code)
+                                && self.input[self.pos..].starts_with("\\u")
+                            {
+                                self.pos += 2;
+
+                                let low = self.parse_hex_escape()?;
+
+                                if !(0xdc00..0xe000).contains(&low) {
+                                    return Err(JsonError::InvalidEscape(escape_start));
+                                }
+
+                                code = 0x10000 + ((code - 0xd800) << 10) + (low - 0xdc00);
+                            }
+
+                            char::from_u32(code).ok_or(JsonError::InvalidEscape(escape_start))?
+                        }
+                        _ => return Err(JsonError::InvalidEscape(escape_start)),
+                    };
+
+                    s.push(c);
+                }
+                c if c < ' ' => return Err(JsonError::UnexpectedCharacter(c, self.pos - 1)),
+                c => s.push(c),
+            }
+        }
+    }
+
+    fn parse_array(&mut self, depth: usize) -> Result<Json, JsonError> {
+        self.expect('[')?;
+        self.skip_whitespace();
+
+        let mut values = Vec::new();
+
+        if self.peek() == Some(']') {
+            self.pos += 1;
+
+            return Ok(Json::Array(values));
+        }
+
+        loop {
+            values.push(self.parse_value(depth + 1)?);
+
+            self.skip_whitespace();
+
+            match self.next() {
+                Some(',') => continue,
+                Some(']') => return Ok(Json::Array(values)),
+                Some(c) => return Err(JsonError::UnexpectedCharacter(c, self.pos - 1)),
+                None => return Err(JsonError::UnexpectedEnd),
+            }
+        }
+    }
+
+    fn parse_object(&mut self, depth: usize) -> Result<Json, JsonError> {
+        self.expect('{')?;
+        self.skip_whitespace();
+
+        let mut fields = Vec::new();
+
+        if self.peek() == Some('}') {
+            self.pos += 1;
+
+            return Ok(Json::Object(fields));
+        }
+
+        loop {
+            self.skip_whitespace();
+
+            let key = self.parse_string()?;
+
+            self.skip_whitespace();
+            self.expect(':')?;
+
+            fields.push((key, self.parse_value(depth + 1)?));
+
+            self.skip_whitespace();
+
+            match self.next() {
+                Some(',') => continue,
+                Some('}') => return Ok(Json::Object(fields)),
+                Some(c) => return Err(JsonError::UnexpectedCharacter(c, self.pos - 1)),
+                None => return Err(JsonError::UnexpectedEnd),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_serialize_escapes_strings() {
+        let json = Json::from("say \"hi\"\\\n\tto\u{1}María");
+
+        assert_eq!(
+            "\"say \\\"hi\\\"\\\\\\n\\tto\\u0001María\"",
+            json.to_string()
+        );
+    }
+
+    #[test]
+    fn test_serialize_document() {
+        let json = Json::object([
+            ("id", 3u64.into()),
+            ("ratio", Json::Number(0.5)),
+            ("online", true.into()),
+            ("tags", vec![Json::from("a"), Json::Null].into()),
+            ("empty", Json::object([])),
+        ]);
+
+        assert_eq!(
+            "{\"id\": 3, \"ratio\": 0.5, \"online\": true, \"tags\": [\"a\", null], \"empty\": {}}",
+            json.to_string()
+        );
+        assert_eq!("null", Json::Number(f64::NAN).to_string());
+    }
+
+    #[test]
+    fn test_parse_document() {
+        let json = Json::parse(
+            " {\"username\": \"asd\", \"id\": 12, \"t\": -1.5e2, \"ok\": false, \"list\": [1, [], {}], \"none\": null} ",
+        )
+        .unwrap();
+
+        assert_eq!(Some("asd"), json.get("username").and_then(Json::as_str));
+        assert_eq!(Some(12), json.get("id").and_then(Json::as_u64));
+        assert_eq!(Some(-150.0), json.get("t").and_then(Json::as_f64));
+        assert_eq!(None, json.get("t").and_then(Json::as_u64));
+        assert_eq!(Some(false), json.get("ok").and_then(Json::as_bool));
+        assert_eq!(3, json.get("list").and_then(Json::as_array).unwrap().len());
+        assert_eq!(Some(&Json::Null), json.get("none"));
+        assert_eq!(None, json.get("missing"));
+    }
+
+    #[test]
+    fn test_parse_string_escapes() {
+        assert_eq!(
+            Json::from("a\"b\\c/d\ne\u{e9}\u{1f600}"),
+            Json::parse("\"a\\\"b\\\\c\\/d\\ne\\u00e9\\ud83d\\ude00\"").unwrap()
+        );
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let json = Json::object([
+            ("message", "line one\nline \"two\" \\ {}".into()),
+            ("list", vec![Json::from(1u64), Json::from("\u{7}")].into()),
+        ]);
+
+        assert_eq!(json, Json::parse(&json.to_string()).unwrap());
+    }
+
+    #[test]
+    fn test_parse_errors() {
+        assert_eq!(Err(JsonError::UnexpectedEnd), Json::parse(""));
+        assert_eq!(Err(JsonError::UnexpectedEnd), Json::parse("[1, 2"));
+        assert_eq!(Err(JsonError::UnexpectedEnd), Json::parse("\"open"));
+        assert_eq!(
+            Err(JsonError::UnexpectedCharacter('}', 5)),
+            Json::parse("[1, 2}")
+        );
+        assert_eq!(Err(JsonError::TrailingCharacters(3)), Json::parse("{} {}"));
+        assert_eq!(Err(JsonError::TrailingCharacters(1)), Json::parse("01"));
+        assert!(matches!(
+            Json::parse("1."),
+            Err(JsonError::InvalidNumber(_))
+        ));
+        assert!(matches!(
+            Json::parse("\"\\x\""),
+            Err(JsonError::InvalidEscape(1))
+        ));
+        assert!(matches!(
+            Json::parse("\"\\ud83d\\u0041\""),
+            Err(JsonError::InvalidEscape(1))
+        ));
+        assert!(matches!(
+            Json::parse("\"tab\there\""),
+            Err(JsonError::UnexpectedCharacter('\t', 4))
+        ));
+        assert_eq!(Err(JsonError::TooDeep), Json::parse(&"[".repeat(1000)));
+    }
+}
@@ -1,2 +1,15 @@
+pub mod assets;
+pub mod chat;
 pub mod error;
 pub mod http;
+pub mod json;
+pub mod pin;
+pub mod pool;
+pub mod rooms;
+pub mod router;
+pub mod session;
+pub mod store;
+pub mod template;
+
+#[cfg(test)]
+mod test_support;
@@ -1,136 +1,268 @@
 // Data to be sent with qr code
 // Server's ip and port information
 
-use smoll_chat::http::{get_mime_type, HttpRequest, HttpResponse};
+use smoll_chat::chat::{ChatEvent, ChatState, Member, UserMessage, DEFAULT_HISTORY_SIZE};
+use smoll_chat::error::{
+    HandshakeError, OptionError, PinError, ReadError, RoomError, RouteAlreadyRegistered,
+    WebSocketError,
+};
+use smoll_chat::http::compression;
+use smoll_chat::http::static_files::StaticFiles;
+use smoll_chat::http::websocket::{self, Message, WebSocket};
+use smoll_chat::http::{
+    get_mime_type, percent_encode, server_sent_event, Cookie, HttpRequest, HttpResponse,
+    RequestLimits, RequestReader, SameSite,
+};
+use smoll_chat::json::Json;
+use smoll_chat::pin::{JoinPin, PinSetting};
+use smoll_chat::pool::{BoundedSpawner, ThreadPool};
+use smoll_chat::rooms::{Rooms, DEFAULT_MAX_ROOMS};
+use smoll_chat::router::Router;
+use smoll_chat::session::Sessions;
+use smoll_chat::store::StoreOptions;
+use smoll_chat::template::Template;
 use std::env;
 use std::fs::File;
-use std::io::{Read, Write};
-use std::net::TcpListener;
+use std::io::{self, Read, Write};
+use std::net::{TcpListener, TcpStream};
 use std::path::PathBuf;
-use std::sync::mpsc::{self, Sender};
+use std::process;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
 use std::thread;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 
 use local_ip_address::local_ip;
 use qrcode::QrCode;
 
-struct UserMessage {
-    pub username: String,
-    pub message: String,
-}
-
-impl UserMessage {
-    pub fn new(username: String, message: String) -> Self {
-        Self { username, message }
-    }
-}
-
 struct SmollChatOpts {
     pub port: u32,
     pub qrcode: bool,
-    pub static_dir: PathBuf,
+    pub static_dir: Option<PathBuf>,
     pub room_name: String,
+    pub request_limits: RequestLimits,
+    pub keep_alive_timeout: Duration,
+    pub max_requests: usize,
+    pub workers: usize,
+    pub max_streams: usize,
+    pub history_size: usize,
+    pub max_rooms: usize,
+    pub data_dir: PathBuf,
+    pub store_options: StoreOptions,
+    pub session_timeout: Duration,
+    pub pin: PinSetting,
+    pub max_pin_attempts: u32,
+    pub pin_lockout: Duration,
+    pub pin_in_qrcode: bool,
+    pub shutdown_timeout: Duration,
+    pub sniff_mime_types: bool,
+    pub compression: bool,
+    pub compression_threshold: usize,
 }
 
 impl SmollChatOpts {
+    /// The size from which bodies are compressed, if they are at all.
+    pub fn min_compressed_size(&self) -> Option<usize> {
+        self.compression.then_some(self.compression_threshold)
+    }
+
     pub fn default() -> Self {
         Self {
             port: 8080,
             qrcode: false,
-            static_dir: env::current_dir().unwrap(),
+            static_dir: None,
             room_name: String::from("Room"),
+            request_limits: RequestLimits::default(),
+            keep_alive_timeout: Duration::from_secs(5),
+            max_requests: 100,
+            workers: 64,
+            max_streams: 256,
+            history_size: DEFAULT_HISTORY_SIZE,
+            max_rooms: DEFAULT_MAX_ROOMS,
+            data_dir: env::current_dir().unwrap().join("data"),
+            store_options: StoreOptions::default(),
+            session_timeout: Duration::from_secs(24 * 60 * 60),
+            pin: PinSetting::Off,
+            max_pin_attempts: 5,
+            pin_lockout: Duration::from_secs(5 * 60),
+            pin_in_qrcode: false,
+            shutdown_timeout: Duration::from_secs(10),
+            sniff_mime_types: false,
+            compression: true,
+            compression_threshold: compression::DEFAULT_MIN_SIZE,
         }
     }
 
-    pub fn parse() -> Self {
-        let env_file = File::open(".env");
+    /// Reads the options from the `.env` file in the working directory if
+    /// there is one, and from the command line otherwise.
+    pub fn parse() -> Result<Self, OptionError> {
+        match File::open(".env") {
+            Ok(mut env_file) => {
+                let mut buf = String::new();
+
+                env_file.read_to_string(&mut buf)?;
 
-        match env_file {
-            Ok(file) => Self::parse_env(file),
-            Err(_) => Self::parse_args(env::args()),
+                Self::parse_env(&buf)
+            }
+            Err(_) => Self::parse_args(env::args().skip(1)),
         }
     }
 
-    pub fn parse_args(mut args: env::Args) -> Self {
+    /// Parses `--key value` pairs, using the keys of the env file. `-p` is
+    /// short for `--port`.
+    pub fn parse_args(mut args: impl Iterator<Item = String>) -> Result<Self, OptionError> {
         let mut opts_parsed = Self::default();
 
-        while let Some(opt) = args.next() {
-            match opt.as_str() {
-                "--port" | "-p" => {
-                    opts_parsed.port = args
-                        .next()
-                        .expect("Not enough arguments passed")
-                        .parse::<u32>()
-                        .expect("Invalid port number passed");
-                }
-                "--qrcode" => {
-                    opts_parsed.qrcode = args
-                        .next()
-                        .expect("Not enough arguments passed")
-                        .parse::<bool>()
-                        .expect("Invalid boolean passed")
-                }
-                "--static-dir" => {
-                    opts_parsed.static_dir =
-                        PathBuf::from(args.next().expect("Not enough arguments passed"))
-                }
-                "--room-name" => {
-                    opts_parsed.room_name = args.next().expect("Not enough arguments passed")
-                }
-                _ => (),
-            }
+        while let Some(arg) = args.next() {
+            let key = match arg.as_str() {
+                "-p" => "port",
+                arg => arg
+                    .strip_prefix("--")
+                    .ok_or_else(|| OptionError::Unknown(arg.to_string()))?,
+            };
+
+            let value = args
+                .next()
+                .ok_or_else(|| OptionError::MissingValue(key.to_string()))?;
+
+            opts_parsed.set(key, &value)?;
         }
 
-        opts_parsed
+        Ok(opts_parsed)
     }
 
-    pub fn parse_env(mut env_file: File) -> Self {
+    /// Parses `key=value` lines, skipping blank ones and `#` comments.
+    pub fn parse_env(env: &str) -> Result<Self, OptionError> {
         let mut opts_parsed = Self::default();
 
-        let mut buf = String::new();
+        for line in env.lines().map(str::trim) {
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
 
-        env_file
-            .read_to_string(&mut buf)
-            .expect("Error reading opts file");
+            let (key, value) = line
+                .split_once('=')
+                .ok_or_else(|| OptionError::MissingValue(line.to_string()))?;
 
-        buf.lines().for_each(|l| {
-            let mut line = l.split("=");
+            opts_parsed.set(key.trim(), value.trim())?;
+        }
 
-            match line.next().expect("Error in env file formatting") {
-                "port" => {
-                    opts_parsed.port = line
-                        .next()
-                        .expect("No value provided in env file")
-                        .parse::<u32>()
-                        .expect("Invalid port number passed")
-                }
-                "qrcode" => {
-                    opts_parsed.qrcode = line
-                        .next()
-                        .expect("No value provided in env file")
-                        .parse::<bool>()
-                        .expect("Invalid boolean passed")
-                }
-                "static-dir" => {
-                    opts_parsed.static_dir =
-                        PathBuf::from(line.next().expect("No value provided in env file"))
-                }
-                "room-name" => {
-                    opts_parsed.room_name = line
-                        .next()
-                        .expect("No value provided in env file")
-                        .to_string()
+        Ok(opts_parsed)
+    }
+
+    /// Sets the option with the key to the value, both as they are written in
+    /// the env file.
+    fn set(&mut self, key: &str, value: &str) -> Result<(), OptionError> {
+        match key {
+            "port" => self.port = parse_option(key, value, "a port number")?,
+            "qrcode" => self.qrcode = parse_option(key, value, "true or false")?,
+            "static-dir" => self.static_dir = Some(PathBuf::from(value)),
+            "room-name" => self.room_name = value.to_string(),
+            "max-header-size" => {
+                self.request_limits.max_header_size = parse_option(key, value, "a size in bytes")?
+            }
+            "max-body-size" => {
+                self.request_limits.max_body_size = parse_option(key, value, "a size in bytes")?
+            }
+            "keep-alive-timeout" => self.keep_alive_timeout = parse_seconds(key, value)?,
+            "max-requests" => self.max_requests = parse_option(key, value, "a number of requests")?,
+            "workers" => {
+                self.workers = match parse_option(key, value, "a number of workers above 0")? {
+                    0 => {
+                        return Err(OptionError::invalid(
+                            key,
+                            value,
+                            "a number of workers above 0",
+                        ))
+                    }
+                    workers => workers,
                 }
-                _ => (),
             }
-        });
+            "max-streams" => self.max_streams = parse_option(key, value, "a number of streams")?,
+            "history-size" => self.history_size = parse_option(key, value, "a number of messages")?,
+            "max-rooms" => self.max_rooms = parse_option(key, value, "a number of rooms")?,
+            "data-dir" => self.data_dir = PathBuf::from(value),
+            "fsync" => {
+                self.store_options.sync =
+                    parse_option(key, value, "always, never or a number of seconds")?
+            }
+            "max-log-size" => {
+                self.store_options.max_file_size = parse_option(key, value, "a size in bytes")?
+            }
+            "max-log-age" => {
+                self.store_options.max_age =
+                    Some(parse_seconds(key, value)?).filter(|age| !age.is_zero())
+            }
+            "session-timeout" => self.session_timeout = parse_seconds(key, value)?,
+            "pin" => {
+                self.pin = parse_option(key, value, "off, random or a number of 4 to 12 digits")?
+            }
+            "max-pin-attempts" => {
+                self.max_pin_attempts = parse_option(key, value, "a number of attempts")?
+            }
+            "pin-lockout" => self.pin_lockout = parse_seconds(key, value)?,
+            "pin-in-qrcode" => self.pin_in_qrcode = parse_option(key, value, "true or false")?,
+            "shutdown-timeout" => self.shutdown_timeout = parse_seconds(key, value)?,
+            "sniff-mime-types" => {
+                self.sniff_mime_types = parse_option(key, value, "true or false")?
+            }
+            "compression" => self.compression = parse_option(key, value, "true or false")?,
+            "compression-threshold" => {
+                self.compression_threshold = parse_option(key, value, "a size in bytes")?
+            }
+            _ => return Err(OptionError::Unknown(key.to_string())),
+        }
 
-        opts_parsed
+        Ok(())
     }
 }
 
-fn render_server_qr_code(address: &str) {
-    let code = QrCode::new(format!("http://{}", address)).unwrap();
+fn parse_option<T: FromStr>(
+    key: &str,
+    value: &str,
+    expected: &'static str,
+) -> Result<T, OptionError> {
+    value
+        .parse()
+        .map_err(|_| OptionError::invalid(key, value, expected))
+}
+
+fn parse_seconds(key: &str, value: &str) -> Result<Duration, OptionError> {
+    parse_option(key, value, "a number of seconds").map(Duration::from_secs)
+}
+
+/// How long a `/new-message` request waits for new messages before the client
+/// is told there are none and has to poll again.
+const LONG_POLL_TIMEOUT: Duration = Duration::from_secs(25);
+
+/// How long an event stream may go quiet before a heartbeat is sent on it.
+const EVENT_STREAM_HEARTBEAT: Duration = Duration::from_secs(15);
+
+const SESSION_COOKIE: &str = "session";
+
+/// How many messages `/history` returns when the client does not say.
+const DEFAULT_HISTORY_PAGE: usize = 50;
+
+/// WebSocket clients answer the pings sent on quiet connections, so one that
+/// sends nothing for this long is gone.
+const WEBSOCKET_IDLE_TIMEOUT: Duration = Duration::from_secs(45);
+
+/// How often a WebSocket connection checks whether its client went away while
+/// waiting for chat events.
+const WEBSOCKET_POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+const MAX_WEBSOCKET_MESSAGE: usize = 64 * 1024;
+
+/// Renders a QR code of the server's address, carrying the PIN along when
+/// it is given so scanning it is all a user needs to join.
+fn render_server_qr_code(address: &str, pin: Option<&str>) {
+    let url = match pin {
+        Some(pin) => format!("http://{}/?pin={}", address, pin),
+        None => format!("http://{}", address),
+    };
+
+    let code = QrCode::new(url).unwrap();
 
     let qr_term = code
         .render()
@@ -141,189 +273,1177 @@ fn render_server_qr_code(address: &str) {
     println!("{qr_term}");
 }
 
-fn main() {
-    let options = SmollChatOpts::parse();
+/// Renders one of the page templates with the context. Pages contain what is
+/// going on in the chat, so browsers are told not to keep them around.
+fn render_page(
+    static_files: &StaticFiles,
+    status_code: u32,
+    status_message: &str,
+    page: &str,
+    context: &Json,
+) -> HttpResponse {
+    let source = match static_files.read(page) {
+        Ok(Some(source)) => source,
+        Ok(None) => return static_files.not_found(),
+        Err(e) => {
+            eprintln!("Encountered error retrieving resource: {e}");
 
-    let address = format!("{}:{}", local_ip().unwrap().to_string(), options.port);
+            return static_files.not_found();
+        }
+    };
 
-    let listener = TcpListener::bind(&address).expect("Failed to initialize server");
+    let load = |name: &str| match static_files.read(name) {
+        Ok(source) => source.map(|s| String::from_utf8_lossy(&s).into_owned()),
+        Err(e) => {
+            eprintln!("Encountered error retrieving resource: {e}");
 
-    if options.qrcode {
-        render_server_qr_code(&address);
+            None
+        }
+    };
+
+    let rendered = Template::parse(&String::from_utf8_lossy(&source))
+        .and_then(|template| template.render(context, &load));
+
+    match rendered {
+        Ok(content) => HttpResponse::builder()
+            .http_version("HTTP/1.1")
+            .status_code(status_code)
+            .status_message(status_message)
+            .add_header("Content-Type", get_mime_type(page))
+            .add_header("Cache-Control", "no-store")
+            .body(&content)
+            .build(),
+        Err(e) => {
+            eprintln!("Encountered error rendering {page}: {e}");
+
+            HttpResponse::builder()
+                .http_version("HTTP/1.1")
+                .status_code(500)
+                .status_message("Internal Server Error")
+                .build()
+        }
     }
+}
 
-    println!("Server now running at http://{}", address);
+/// Returns the username of the session the client's cookie belongs to.
+fn session_username(sessions: &Sessions, request: &HttpRequest) -> Option<String> {
+    let token = request.cookie(SESSION_COOKIE)?;
 
-    let mut message_queue: Vec<Sender<UserMessage>> = Vec::new();
+    sessions.get(token).map(|session| session.username)
+}
 
-    for stream in listener.incoming() {
-        let mut inc = stream.unwrap();
+/// Writes chat events past the cursor to the client as server-sent events
+/// until it goes away. A comment is sent whenever there has been nothing to
+/// send for a while, so dead connections are noticed.
+fn stream_events(chat: &ChatState, mut cursor: u64, writer: &mut dyn Write) -> io::Result<()> {
+    writer.write_all(b"retry: 1000\n\n")?;
+    writer.flush()?;
 
-        let mut request = [0; 1024];
+    loop {
+        let (events, next) = chat.wait_for_events(cursor, EVENT_STREAM_HEARTBEAT);
 
-        inc.read(&mut request).unwrap();
+        if events.is_empty() {
+            writer.write_all(b": heartbeat\n\n")?;
+        }
 
-        let raw_request = String::from_utf8(request.to_vec()).unwrap();
+        for (i, event) in events.iter().enumerate() {
+            let id = next - (events.len() - i - 1) as u64;
 
-        let request = HttpRequest::parse(&raw_request);
+            writer.write_all(
+                server_sent_event(Some(id), event.name(), &event.to_json().to_string()).as_bytes(),
+            )?;
+        }
 
-        if request.method == "GET" {
-            if request.resource == "/" {
-                println!("{}", format!("{}/index.html", options.static_dir.display()));
-                match std::fs::read_to_string(format!(
-                    "{}/index.html",
-                    options.static_dir.display()
-                )) {
-                    Ok(mut content) => {
-                        let mut content_split: Vec<&str> = content.split("{{}}").collect();
+        writer.flush()?;
 
-                        content_split.insert(1, &options.room_name);
+        if chat.is_closed() {
+            return Ok(());
+        }
 
-                        content = content_split.join("");
+        cursor = next;
+    }
+}
+
+fn websocket_event(id: u64, event: &ChatEvent) -> String {
+    Json::object([
+        ("event", event.name().into()),
+        ("id", id.into()),
+        ("data", event.to_json()),
+    ])
+    .to_string()
+}
 
-                        let response = HttpResponse::builder()
-                            .add_header("Content-Length", &format!("{}", content.len()))
-                            .add_header("Content-Type", "text/html")
-                            .body(&content);
+/// Relays chat events past the cursor to a WebSocket client and publishes the
+/// messages it sends, until either side closes the connection.
+fn websocket_chat(
+    chat: &ChatState,
+    username: &str,
+    mut cursor: u64,
+    reader: &mut (dyn Read + Send),
+    writer: &mut (dyn Write + Send),
+) -> io::Result<()> {
+    let mut socket = WebSocket::new(reader, writer, MAX_WEBSOCKET_MESSAGE);
+    let sender = socket.sender();
+    let closed = AtomicBool::new(false);
 
-                        inc.write(response.build().to_string().as_bytes()).unwrap();
+    thread::scope(|scope| {
+        scope.spawn(|| {
+            let mut last_sent = Instant::now();
+
+            while !closed.load(Ordering::Relaxed) {
+                let (events, next) = chat.wait_for_events(cursor, WEBSOCKET_POLL_INTERVAL);
+
+                let sent = if events.is_empty() {
+                    if last_sent.elapsed() < EVENT_STREAM_HEARTBEAT {
+                        continue;
                     }
-                    Err(e) => eprintln!("Encountered error retrieving resource: {e}"),
+
+                    sender.ping(Vec::new())
+                } else {
+                    events.iter().enumerate().try_for_each(|(i, event)| {
+                        let id = next - (events.len() - i - 1) as u64;
+
+                        sender.send_text(&websocket_event(id, event))
+                    })
+                };
+
+                if sent.is_err() {
+                    closed.store(true, Ordering::Relaxed);
+                } else if chat.is_closed() {
+                    // The client answers with a close frame, which ends the
+                    // reading side
+                    let _ = sender.close(1001, "Server shutting down");
+
+                    closed.store(true, Ordering::Relaxed);
                 }
-            } else if request.resource == "/chat" {
-                match std::fs::read_to_string(format!("{}/chat.html", options.static_dir.display()))
-                {
-                    Ok(mut content) => {
-                        let mut content_split: Vec<&str> = content.split("{{}}").collect();
 
-                        content_split.insert(1, &options.room_name);
+                last_sent = Instant::now();
+                cursor = next;
+            }
+        });
 
-                        content = content_split.join("");
+        let result = loop {
+            match socket.read_message() {
+                Ok(Message::Text(text)) => {
+                    chat.publish(ChatEvent::Message(UserMessage::new(
+                        username.to_string(),
+                        text,
+                    )));
+                }
+                Ok(Message::Close(_)) => break Ok(()),
+                Ok(_) => (),
+                Err(WebSocketError::Io(e)) => break Err(e),
+                Err(_) => break Ok(()),
+            }
+        };
 
-                        let response = HttpResponse::builder()
-                            .add_header("Content-Length", &format!("{}", content.len()))
-                            .add_header("Content-Type", "text/html")
-                            .body(&content);
+        // Stops the sending side too, however reading ended
+        closed.store(true, Ordering::Relaxed);
 
-                        inc.write(response.build().to_string().as_bytes()).unwrap();
-                    }
-                    Err(e) => eprintln!("Encountered error retrieving resource: {e}"),
+        result
+    })
+}
+
+fn handshake_error_response(error: &HandshakeError) -> HttpResponse {
+    match error {
+        HandshakeError::UnsupportedVersion => HttpResponse::builder()
+            .http_version("HTTP/1.1")
+            .status_code(426)
+            .status_message("Upgrade Required")
+            .add_header("Content-Type", "text/plain")
+            .add_header("Sec-WebSocket-Version", "13")
+            .body(&error.to_string())
+            .build(),
+        _ => HttpResponse::bad_request(&error.to_string()),
+    }
+}
+
+fn pin_error_status(error: &PinError) -> (u32, &'static str) {
+    match error {
+        PinError::Incorrect { .. } => (403, "Forbidden"),
+        PinError::LockedOut(_) => (429, "Too Many Requests"),
+    }
+}
+
+/// Builds the response sent back for a request that could not be read, if the
+/// client is still there to receive one.
+fn read_error_response(error: &ReadError) -> Option<HttpResponse> {
+    let (status_code, status_message) = match error {
+        ReadError::Parse(_) => (400, "Bad Request"),
+        ReadError::HeaderTooLarge => (431, "Request Header Fields Too Large"),
+        ReadError::BodyTooLarge => (413, "Content Too Large"),
+        ReadError::Io(_) | ReadError::ConnectionClosed => return None,
+    };
+
+    let content = error.to_string();
+
+    Some(
+        HttpResponse::builder()
+            .http_version("HTTP/1.1")
+            .status_code(status_code)
+            .status_message(status_message)
+            .add_header("Content-Type", "text/plain")
+            .add_header("Connection", "close")
+            .body(&content)
+            .build(),
+    )
+}
+
+/// Turns away a request that needs a session from a client without one.
+fn not_logged_in() -> HttpResponse {
+    HttpResponse::builder()
+        .http_version("HTTP/1.1")
+        .status_code(401)
+        .status_message("Unauthorized")
+        .add_header("Content-Type", "text/plain")
+        .body("Not logged in")
+        .build()
+}
+
+fn service_unavailable() -> HttpResponse {
+    HttpResponse::builder()
+        .http_version("HTTP/1.1")
+        .status_code(503)
+        .status_message("Service Unavailable")
+        .add_header("Content-Type", "text/plain")
+        .add_header("Retry-After", "1")
+        .add_header("Connection", "close")
+        .body("The server is too busy to handle the request, try again shortly")
+        .build()
+}
+
+/// Serves requests off of a connection until the client asks for it to be
+/// closed, it sits idle for longer than the keep-alive timeout, or it reaches
+/// the maximum number of requests per connection.
+fn handle_connection(
+    mut stream: TcpStream,
+    router: &Router,
+    options: &SmollChatOpts,
+    shutting_down: &AtomicBool,
+    streams: &BoundedSpawner,
+) -> io::Result<()> {
+    stream.set_read_timeout(Some(options.keep_alive_timeout))?;
+
+    let mut reader = RequestReader::new(stream.try_clone()?, options.request_limits);
+
+    let mut served = 0;
+
+    loop {
+        let mut request = match reader.read_request() {
+            Ok(request) => request,
+            Err(e) => {
+                if let Some(response) = read_error_response(&e) {
+                    eprintln!("Encountered error reading request: {e}");
+
+                    response.write_to(&mut stream)?;
                 }
-            } else if request.resource == "/new-message" {
-                let (s, r) = mpsc::channel();
-
-                message_queue.push(s);
-
-                thread::spawn(move || {
-                    let receiver: mpsc::Receiver<UserMessage> = r;
-                    let mut client = inc;
-
-                    loop {
-                        match receiver.try_recv() {
-                            Ok(message) => {
-                                let json = format!(
-                                    "{{\"username\": \"{}\", \"message\": \"{}\"}}",
-                                    message.username, message.message
-                                );
-
-                                let response = HttpResponse::builder()
-                                    .http_version("HTTP/1.1")
-                                    .status_code(200)
-                                    .status_message("OK")
-                                    .add_header("Content-Type", "application/json")
-                                    .add_header("Content-Length", &format!("{}", json.len()))
-                                    .body(&json);
-
-                                client
-                                    .write(response.build().to_string().as_bytes())
-                                    .unwrap();
-                                client.flush().unwrap();
-
-                                break;
-                            }
-                            Err(_) => (),
-                        }
-
-                        thread::sleep(Duration::from_secs(1));
-                    }
-                });
-            } else if request.resource.starts_with("/static/") {
-                match std::fs::read_to_string(format!(
-                    "{}/{}",
-                    options.static_dir.display(),
-                    request.resource.splitn(3, "/").skip(2).next().unwrap()
-                )) {
-                    Ok(content) => {
-                        let response = HttpResponse::builder()
-                            .http_version("HTTP/1.1")
-                            .status_code(200)
-                            .status_message("OK")
-                            .add_header("Content-Type", &get_mime_type(&request.resource))
-                            .add_header("Content-Length", &format!("{}", content.len()))
-                            .body(&content);
-
-                        inc.write(response.build().to_string().as_bytes()).unwrap();
+
+                return Ok(());
+            }
+        };
+
+        request.peer_addr = stream.peer_addr().ok();
+
+        served += 1;
+
+        let mut response = router.route(&mut request);
+
+        if let Some(min_size) = options.min_compressed_size() {
+            compression::compress_response(&request, &mut response, min_size);
+        }
+
+        if let Some(upgrade) = response.take_upgrade() {
+            return hand_off(streams, stream, move |stream| {
+                response.write_to(stream)?;
+
+                stream.set_read_timeout(Some(WEBSOCKET_IDLE_TIMEOUT))?;
+
+                upgrade(&mut reader, stream)
+            });
+        }
+
+        // Streamed bodies have no length, so their end is marked by closing
+        let keep_alive = request.keep_alive()
+            && served < options.max_requests
+            && !response.is_streaming()
+            && !shutting_down.load(Ordering::SeqCst);
+
+        if keep_alive {
+            response.headers_mut().insert("Connection", "keep-alive");
+            response.headers_mut().insert(
+                "Keep-Alive",
+                &format!(
+                    "timeout={}, max={}",
+                    options.keep_alive_timeout.as_secs(),
+                    options.max_requests - served
+                ),
+            );
+        } else {
+            response.headers_mut().insert("Connection", "close");
+        }
+
+        if response.is_streaming() {
+            return hand_off(streams, stream, move |stream| response.write_to(stream));
+        }
+
+        response.write_to(&mut stream)?;
+
+        if !keep_alive {
+            return Ok(());
+        }
+    }
+}
+
+/// Gives the connection a thread of its own to finish a response that lasts
+/// as long as the client stays, so it does not hold on to a worker.
+fn hand_off<F>(streams: &BoundedSpawner, mut stream: TcpStream, serve: F) -> io::Result<()>
+where
+    F: FnOnce(&mut TcpStream) -> io::Result<()> + Send + 'static,
+{
+    let mut overflow = stream.try_clone()?;
+
+    let spawned = streams.try_spawn(move || {
+        if let Err(e) = serve(&mut stream) {
+            eprintln!("Encountered error writing response: {e}");
+        }
+    });
+
+    if let Err(e) = spawned {
+        eprintln!("Turning away stream: {e}");
+
+        service_unavailable().write_to(&mut overflow)?;
+    }
+
+    Ok(())
+}
+
+/// Looks up the room named in the request's path, or the default room for
+/// paths that do not name one.
+fn requested_room(rooms: &Rooms, request: &HttpRequest) -> Option<Arc<ChatState>> {
+    match request.param("room") {
+        Some(room) => rooms.get(room),
+        None => Some(rooms.default_room()),
+    }
+}
+
+/// Responds with a page of the room's message history, older than the message
+/// `before` if given.
+fn room_history(rooms: &Rooms, sessions: &Sessions, request: &HttpRequest) -> HttpResponse {
+    if session_username(sessions, request).is_none() {
+        return not_logged_in();
+    }
+
+    let Some(chat) = requested_room(rooms, request) else {
+        return HttpResponse::not_found();
+    };
+
+    let before = match request.query("before").map(|b| b.parse::<u64>()) {
+        Some(Ok(before)) => Some(before),
+        Some(Err(_)) => return HttpResponse::bad_request("Invalid message id"),
+        None => None,
+    };
+
+    let limit = match request.query("limit").map(|l| l.parse::<usize>()) {
+        Some(Ok(limit)) => limit,
+        Some(Err(_)) => return HttpResponse::bad_request("Invalid limit"),
+        None => DEFAULT_HISTORY_PAGE,
+    };
+
+    // Read before the history so that nothing posted in between is missed
+    // by a client that picks up live events from the cursor
+    let cursor = chat.cursor();
+    let (messages, more) = chat.history(before, limit);
+
+    let json = Json::object([
+        ("cursor", cursor.into()),
+        ("more", more.into()),
+        (
+            "messages",
+            messages
+                .iter()
+                .map(UserMessage::to_json)
+                .collect::<Vec<Json>>()
+                .into(),
+        ),
+    ]);
+
+    json_response(200, "OK", &json)
+}
+
+/// Streams the room's events as server-sent events.
+fn room_events(rooms: &Rooms, sessions: &Sessions, request: &HttpRequest) -> HttpResponse {
+    let Some(username) = session_username(sessions, request) else {
+        return not_logged_in();
+    };
+
+    let Some(chat) = requested_room(rooms, request) else {
+        return HttpResponse::not_found();
+    };
+
+    // Reconnecting browsers pick up right after the last event they saw
+    let cursor = match request
+        .get_header("Last-Event-ID")
+        .map(|c| c.parse::<u64>())
+    {
+        Some(Ok(cursor)) => cursor,
+        Some(Err(_)) => return HttpResponse::bad_request("Invalid Last-Event-ID"),
+        None => chat.cursor(),
+    };
+
+    HttpResponse::builder()
+        .http_version("HTTP/1.1")
+        .status_code(200)
+        .status_message("OK")
+        .add_header("Content-Type", "text/event-stream")
+        .add_header("Cache-Control", "no-store")
+        .stream(move |writer| {
+            chat.connect(&username);
+
+            let streamed = stream_events(&chat, cursor, writer);
+
+            chat.disconnect(&username);
+
+            streamed
+        })
+        .build()
+}
+
+fn room_path(chat: &ChatState) -> String {
+    format!("/rooms/{}", percent_encode(chat.room()))
+}
+
+fn room_json(chat: &ChatState) -> Json {
+    Json::object([
+        ("name", chat.room().into()),
+        ("path", room_path(chat).into()),
+        ("members", chat.connected().into()),
+    ])
+}
+
+/// The values the join page is rendered with. The name a user entered is
+/// kept so they do not have to type it again after a mistake.
+fn index_context(room_name: &str, pin_required: bool, username: &str, error: Option<&str>) -> Json {
+    Json::object([
+        ("title", format!("Chat Room {room_name}").into()),
+        ("room_name", room_name.into()),
+        ("pin_required", pin_required.into()),
+        ("username", username.into()),
+        ("error", error.map_or(Json::Null, Json::from)),
+    ])
+}
+
+fn lobby_context(rooms: &Rooms) -> Json {
+    let rooms: Vec<Json> = rooms
+        .list()
+        .iter()
+        .map(|chat| {
+            let members = chat.connected();
+
+            Json::object([
+                ("name", chat.room().into()),
+                ("path", room_path(chat).into()),
+                (
+                    "online",
+                    match members {
+                        1 => "1 member online".to_string(),
+                        n => format!("{n} members online"),
                     }
-                    Err(e) => eprintln!("Encountered error retrieving resource: {e}"),
-                }
+                    .into(),
+                ),
+            ])
+        })
+        .collect();
+
+    Json::object([("title", "Chat Rooms".into()), ("rooms", rooms.into())])
+}
+
+/// The values a room's page is rendered with: its members and the latest
+/// history, so the page shows the conversation as soon as it loads.
+fn chat_context(chat: &ChatState, username: &str) -> Json {
+    // Read before the history, as the history endpoint does
+    let cursor = chat.cursor();
+    let (messages, more) = chat.history(None, DEFAULT_HISTORY_PAGE);
+
+    let oldest = messages.first().map_or(Json::Null, |m| m.id.into());
+    let newest = messages.last().map_or(0u64, |m| m.id);
+
+    let messages: Vec<Json> = messages
+        .iter()
+        .map(|m| {
+            Json::object([
+                ("username", m.username.as_str().into()),
+                ("message", m.message.as_str().into()),
+                ("own", (m.username == username).into()),
+            ])
+        })
+        .collect();
+
+    let members: Vec<Json> = chat.members().iter().map(Member::to_json).collect();
+
+    Json::object([
+        ("title", format!("Chat Room {}", chat.room()).into()),
+        ("room_name", chat.room().into()),
+        ("username", username.into()),
+        ("cursor", cursor.into()),
+        ("oldest", oldest),
+        ("newest", newest.into()),
+        ("more", more.into()),
+        ("messages", messages.into()),
+        ("members", members.into()),
+    ])
+}
+
+fn json_response(status_code: u32, status_message: &str, json: &Json) -> HttpResponse {
+    HttpResponse::builder()
+        .http_version("HTTP/1.1")
+        .status_code(status_code)
+        .status_message(status_message)
+        .add_header("Content-Type", "application/json")
+        .add_header("Cache-Control", "no-store")
+        .body(&json.to_string())
+        .build()
+}
+
+fn build_router(
+    options: &SmollChatOpts,
+    address: &str,
+    rooms: Arc<Rooms>,
+    sessions: Arc<Sessions>,
+    pin: Option<JoinPin>,
+) -> Result<Router, RouteAlreadyRegistered> {
+    let mut router = Router::new();
+
+    let static_files = StaticFiles::new(options.static_dir.as_deref())
+        .expect("Failed to open the static directory")
+        .sniff(options.sniff_mime_types)
+        .compress(options.min_compressed_size());
+
+    let static_files = Arc::new(static_files);
+
+    let index_files = Arc::clone(&static_files);
+    let room_name = options.room_name.clone();
+    let pin_required = pin.is_some();
+
+    router.get("/", move |_| {
+        let context = index_context(&room_name, pin_required, "", None);
+
+        render_page(&index_files, 200, "OK", "index.html", &context)
+    })?;
+
+    let default_path = room_path(&rooms.default_room());
+
+    router.get("/chat", move |_| {
+        HttpResponse::builder()
+            .http_version("HTTP/1.1")
+            .status_code(303)
+            .status_message("See Other")
+            .add_header("Location", &default_path)
+            .build()
+    })?;
+
+    let lobby_files = Arc::clone(&static_files);
+    let lobby_rooms = Arc::clone(&rooms);
+    let lobby_sessions = Arc::clone(&sessions);
+
+    router.get("/lobby", move |request| {
+        if session_username(&lobby_sessions, request).is_none() {
+            return HttpResponse::builder()
+                .http_version("HTTP/1.1")
+                .status_code(303)
+                .status_message("See Other")
+                .add_header("Location", "/")
+                .build();
+        }
+
+        render_page(
+            &lobby_files,
+            200,
+            "OK",
+            "lobby.html",
+            &lobby_context(&lobby_rooms),
+        )
+    })?;
+
+    let list_rooms = Arc::clone(&rooms);
+    let list_sessions = Arc::clone(&sessions);
+
+    router.get("/rooms", move |request| {
+        if session_username(&list_sessions, request).is_none() {
+            return not_logged_in();
+        }
+
+        let rooms: Vec<Json> = list_rooms.list().iter().map(|r| room_json(r)).collect();
+
+        json_response(200, "OK", &Json::object([("rooms", rooms.into())]))
+    })?;
+
+    let create_rooms = Arc::clone(&rooms);
+    let create_sessions = Arc::clone(&sessions);
+
+    router.post("/rooms", move |request| {
+        if session_username(&create_sessions, request).is_none() {
+            return not_logged_in();
+        }
+
+        let form = match request.form() {
+            Ok(form) => form,
+            Err(e) => return HttpResponse::bad_request(&e.to_string()),
+        };
+
+        let Some(name) = form.get("name") else {
+            return HttpResponse::bad_request("A name is required to create a room");
+        };
+
+        match create_rooms.create(name.trim()) {
+            Ok(chat) => {
+                println!("Room {} was created.", chat.room());
+
+                let mut response = json_response(201, "Created", &room_json(&chat));
+
+                response.headers_mut().insert("Location", &room_path(&chat));
+
+                response
             }
-        } else if request.method == "POST" {
-            if request.resource == "/login" {
-                let response = HttpResponse::builder()
+            Err(e @ RoomError::InvalidName(_)) => HttpResponse::bad_request(&e.to_string()),
+            Err(e @ RoomError::AlreadyExists(_)) => HttpResponse::builder()
+                .http_version("HTTP/1.1")
+                .status_code(409)
+                .status_message("Conflict")
+                .add_header("Content-Type", "text/plain")
+                .body(&e.to_string())
+                .build(),
+            Err(e @ RoomError::TooMany(_)) => HttpResponse::builder()
+                .http_version("HTTP/1.1")
+                .status_code(403)
+                .status_message("Forbidden")
+                .add_header("Content-Type", "text/plain")
+                .body(&e.to_string())
+                .build(),
+            Err(e @ RoomError::Io(_)) => {
+                eprintln!("Encountered error creating room: {e}");
+
+                HttpResponse::builder()
                     .http_version("HTTP/1.1")
-                    .status_code(303)
-                    .status_message("See Other")
-                    .add_header("Content-Type", "text/html")
-                    .add_header("Content-Length", "0")
-                    .add_header("Location", &format!("http://{}/chat", address))
-                    .add_cookie(request.body.as_ref().unwrap());
-
-                println!(
-                    "User {} has joined the chat.",
-                    request
-                        .body
-                        .as_ref()
-                        .unwrap()
-                        .split("=")
-                        .skip(1)
-                        .next()
-                        .unwrap()
+                    .status_code(500)
+                    .status_message("Internal Server Error")
+                    .build()
+            }
+        }
+    })?;
+
+    let page_files = Arc::clone(&static_files);
+    let page_rooms = Arc::clone(&rooms);
+    let page_sessions = Arc::clone(&sessions);
+
+    router.get("/rooms/:room", move |request| {
+        let Some(chat) = requested_room(&page_rooms, request) else {
+            return page_files.not_found();
+        };
+
+        let Some(username) = session_username(&page_sessions, request) else {
+            return HttpResponse::builder()
+                .http_version("HTTP/1.1")
+                .status_code(303)
+                .status_message("See Other")
+                .add_header("Location", "/")
+                .build();
+        };
+
+        let context = chat_context(&chat, &username);
+
+        render_page(&page_files, 200, "OK", "chat.html", &context)
+    })?;
+
+    let members_rooms = Arc::clone(&rooms);
+    let members_sessions = Arc::clone(&sessions);
+
+    router.get("/rooms/:room/members", move |request| {
+        if session_username(&members_sessions, request).is_none() {
+            return not_logged_in();
+        }
+
+        let Some(chat) = requested_room(&members_rooms, request) else {
+            return HttpResponse::not_found();
+        };
+
+        let members: Vec<Json> = chat.members().iter().map(Member::to_json).collect();
+
+        json_response(200, "OK", &Json::object([("members", members.into())]))
+    })?;
+
+    let new_message_rooms = Arc::clone(&rooms);
+    let new_message_sessions = Arc::clone(&sessions);
+
+    router.get("/rooms/:room/new-message", move |request| {
+        if session_username(&new_message_sessions, request).is_none() {
+            return not_logged_in();
+        }
+
+        let Some(chat) = requested_room(&new_message_rooms, request) else {
+            return HttpResponse::not_found();
+        };
+
+        // Clients without a cursor only want what is posted from now on
+        let cursor = match request.query("cursor").map(|c| c.parse::<u64>()) {
+            Some(Ok(cursor)) => cursor,
+            Some(Err(_)) => return HttpResponse::bad_request("Invalid cursor"),
+            None => chat.cursor(),
+        };
+
+        // Waiting is done once the response is handed off, so a long poll
+        // does not hold on to a worker
+        HttpResponse::builder()
+            .http_version("HTTP/1.1")
+            .status_code(200)
+            .status_message("OK")
+            .add_header("Content-Type", "application/json")
+            .add_header("Cache-Control", "no-store")
+            .stream(move |writer| {
+                let (events, cursor) = chat.wait_for_events(cursor, LONG_POLL_TIMEOUT);
+
+                let messages: Vec<Json> = events
+                    .iter()
+                    .filter(|event| matches!(event, ChatEvent::Message(_)))
+                    .map(ChatEvent::to_json)
+                    .collect();
+
+                let notices: Vec<Json> = events
+                    .iter()
+                    .filter(|event| matches!(event, ChatEvent::System(_)))
+                    .map(ChatEvent::to_json)
+                    .collect();
+
+                let json = Json::object([
+                    ("cursor", cursor.into()),
+                    ("messages", messages.into()),
+                    ("notices", notices.into()),
+                ]);
+
+                writer.write_all(json.to_string().as_bytes())
+            })
+            .build()
+    })?;
+
+    // `/history` and `/events` are where the default room's were before there
+    // were several rooms
+    for path in ["/rooms/:room/history", "/history"] {
+        let history_rooms = Arc::clone(&rooms);
+        let history_sessions = Arc::clone(&sessions);
+
+        router.get(path, move |request| {
+            room_history(&history_rooms, &history_sessions, request)
+        })?;
+    }
+
+    for path in ["/rooms/:room/events", "/events"] {
+        let events_rooms = Arc::clone(&rooms);
+        let events_sessions = Arc::clone(&sessions);
+
+        router.get(path, move |request| {
+            room_events(&events_rooms, &events_sessions, request)
+        })?;
+    }
+
+    let ws_rooms = Arc::clone(&rooms);
+    let ws_sessions = Arc::clone(&sessions);
+
+    router.get("/rooms/:room/ws", move |request| {
+        let Some(chat) = requested_room(&ws_rooms, request) else {
+            return HttpResponse::not_found();
+        };
+
+        let Some(username) = session_username(&ws_sessions, request) else {
+            return HttpResponse::bad_request("Log in before joining the chat");
+        };
+
+        // Reconnecting clients pass the id of the last event they saw
+        let cursor = match request.query("cursor").map(|c| c.parse::<u64>()) {
+            Some(Ok(cursor)) => cursor,
+            Some(Err(_)) => return HttpResponse::bad_request("Invalid cursor"),
+            None => chat.cursor(),
+        };
+
+        let response = match websocket::handshake(request) {
+            Ok(response) => response,
+            Err(e) => return handshake_error_response(&e),
+        };
+
+        response
+            .upgrade(move |reader, writer| {
+                chat.connect(&username);
+
+                let result = websocket_chat(&chat, &username, cursor, reader, writer);
+
+                chat.disconnect(&username);
+
+                result
+            })
+            .build()
+    })?;
+
+    let login_files = Arc::clone(&static_files);
+    let login_room_name = options.room_name.clone();
+
+    router.get("/static/*path", move |request| {
+        static_files.serve(request, request.param("path").unwrap())
+    })?;
+
+    let location = format!("http://{}{}", address, room_path(&rooms.default_room()));
+    let login_sessions = Arc::clone(&sessions);
+
+    router.post("/login", move |request| {
+        let form = match request.form() {
+            Ok(form) => form,
+            Err(e) => return HttpResponse::bad_request(&e.to_string()),
+        };
+
+        let entered_username = form.get("username").map_or("", String::as_str);
+
+        let rejected = |status_code, status_message, error: &str| {
+            let context = index_context(
+                &login_room_name,
+                pin_required,
+                entered_username,
+                Some(error),
+            );
+
+            render_page(
+                &login_files,
+                status_code,
+                status_message,
+                "index.html",
+                &context,
+            )
+        };
+
+        if entered_username.is_empty() {
+            return rejected(
+                400,
+                "Bad Request",
+                "A username is required to join the chat",
+            );
+        }
+
+        if let Some(pin) = pin.as_ref() {
+            // Lockouts are kept per address, so without one every guess would
+            // count against the same shared entry
+            let Some(addr) = request.peer_addr.map(|a| a.ip()) else {
+                eprintln!("Rejected login: the connection has no peer address");
+
+                return rejected(
+                    500,
+                    "Internal Server Error",
+                    "The server could not tell where the request came from",
                 );
+            };
 
-                inc.write(response.build().to_string().as_bytes()).unwrap();
-            } else if request.resource == "/message" {
-                while let Some(sender) = message_queue.pop() {
-                    sender
-                        .send(UserMessage::new(
-                            request
-                                .get_header("Cookie")
-                                .unwrap()
-                                .split("=")
-                                .skip(1)
-                                .next()
-                                .unwrap()
-                                .to_string(),
-                            request.body.clone().unwrap(),
-                        ))
-                        .unwrap();
+            let entered = form.get("pin").map_or("", String::as_str);
+
+            if let Err(e) = pin.check(addr, entered) {
+                eprintln!("Rejected login from {addr}: {e}");
+
+                let (status_code, status_message) = pin_error_status(&e);
+
+                let mut response = rejected(status_code, status_message, &e.to_string());
+
+                if let PinError::LockedOut(remaining) = e {
+                    response
+                        .headers_mut()
+                        .insert("Retry-After", &remaining.as_secs().max(1).to_string());
                 }
 
-                let response = HttpResponse::builder()
-                    .http_version("HTTP/1.1")
-                    .status_code(200)
-                    .status_message("OK")
-                    .add_header("Content-Length", "0");
+                return response;
+            }
+        }
+
+        // Logging in again ends the session the browser already has
+        let (token, session) = match request.cookie(SESSION_COOKIE) {
+            Some(previous) => login_sessions.replace(previous, entered_username),
+            None => login_sessions.create(entered_username),
+        };
+
+        println!("User {} has joined the chat.", session.username);
+
+        // The session expires once it goes unused for a while, which a fixed
+        // Max-Age could only cut short, so the cookie lasts until the browser
+        // is closed instead
+        let cookie = Cookie::new(SESSION_COOKIE, &token)
+            .path("/")
+            .http_only(true)
+            .same_site(SameSite::Strict);
 
-                inc.write(response.build().to_string().as_bytes()).unwrap();
+        HttpResponse::builder()
+            .http_version("HTTP/1.1")
+            .status_code(303)
+            .status_message("See Other")
+            .add_header("Content-Type", "text/html")
+            .add_header("Location", &location)
+            .set_cookie(&cookie)
+            .build()
+    })?;
+
+    let logout_sessions = Arc::clone(&sessions);
+
+    router.post("/logout", move |request| {
+        if let Some(session) = request
+            .cookie(SESSION_COOKIE)
+            .and_then(|token| logout_sessions.remove(token))
+        {
+            println!("User {} has logged out.", session.username);
+        }
+
+        HttpResponse::builder()
+            .http_version("HTTP/1.1")
+            .status_code(303)
+            .status_message("See Other")
+            .add_header("Location", "/")
+            .set_cookie(&Cookie::removal(SESSION_COOKIE).path("/"))
+            .build()
+    })?;
+
+    let me_sessions = Arc::clone(&sessions);
+
+    router.get("/me", move |request| {
+        match session_username(&me_sessions, request) {
+            Some(username) => {
+                json_response(200, "OK", &Json::object([("username", username.into())]))
             }
-        } else {
-            let response = HttpResponse::builder()
-                .http_version("HTTP/1.1")
-                .status_code(404)
-                .status_message("Not Found")
-                .add_header("Content-Length", "0");
+            None => not_logged_in(),
+        }
+    })?;
+
+    router.post("/rooms/:room/message", move |request| {
+        let Some(chat) = requested_room(&rooms, request) else {
+            return HttpResponse::not_found();
+        };
+
+        let Some(username) = session_username(&sessions, request) else {
+            return not_logged_in();
+        };
+
+        chat.publish(ChatEvent::Message(UserMessage::new(
+            username,
+            request.body.clone().unwrap_or_default(),
+        )));
+
+        HttpResponse::builder()
+            .http_version("HTTP/1.1")
+            .status_code(200)
+            .status_message("OK")
+            .build()
+    })?;
+
+    Ok(router)
+}
+
+fn main() {
+    let options = match SmollChatOpts::parse() {
+        Ok(options) => Arc::new(options),
+        Err(e) => {
+            eprintln!("{e}");
+
+            process::exit(2);
+        }
+    };
+
+    let address = format!("{}:{}", local_ip().unwrap(), options.port);
+
+    let listener = TcpListener::bind(&address).expect("Failed to initialize server");
+
+    let pin = JoinPin::from_setting(&options.pin, options.max_pin_attempts, options.pin_lockout);
+
+    if options.qrcode {
+        let qr_pin = pin.as_ref().filter(|_| options.pin_in_qrcode);
+
+        render_server_qr_code(&address, qr_pin.map(JoinPin::pin));
+    }
+
+    println!("Server now running at http://{}", address);
+
+    if let Some(pin) = pin.as_ref() {
+        println!("Security code to join: {}", pin.pin());
+    }
+
+    let rooms = Rooms::open(
+        &options.room_name,
+        options.history_size,
+        &options.data_dir,
+        options.store_options,
+    )
+    .expect("Failed to open the rooms' message logs")
+    .max_rooms(options.max_rooms);
+
+    println!(
+        "Hosting {} rooms from {}",
+        rooms.list().len(),
+        options.data_dir.display()
+    );
+
+    let rooms = Arc::new(rooms);
+
+    let router = build_router(
+        &options,
+        &address,
+        Arc::clone(&rooms),
+        Arc::new(Sessions::new(options.session_timeout)),
+        pin,
+    )
+    .expect("Failed to register routes");
+
+    let router = Arc::new(router);
+
+    let pool = ThreadPool::new(options.workers, options.workers);
+    let streams = Arc::new(BoundedSpawner::new(options.max_streams));
+
+    let shutting_down = Arc::new(AtomicBool::new(false));
+
+    let signalled = Arc::clone(&shutting_down);
+    let wake_address = address.clone();
+
+    ctrlc::set_handler(move || {
+        if signalled.swap(true, Ordering::SeqCst) {
+            eprintln!("Shutting down right away");
+
+            process::exit(1);
+        }
+
+        // Accepting blocks until someone connects, so connect to wake it up
+        let _ = TcpStream::connect(&wake_address);
+    })
+    .expect("Failed to set the shutdown signal handler");
+
+    for stream in listener.incoming() {
+        if shutting_down.load(Ordering::SeqCst) {
+            break;
+        }
+
+        let stream = match stream {
+            Ok(stream) => stream,
+            Err(e) => {
+                eprintln!("Encountered error accepting connection: {e}");
+
+                continue;
+            }
+        };
+
+        // Kept around to turn the client away if no worker can take it
+        let mut overflow = stream.try_clone();
+
+        let router = Arc::clone(&router);
+        let options = Arc::clone(&options);
+        let shutting_down = Arc::clone(&shutting_down);
+        let streams = Arc::clone(&streams);
+
+        let job = pool.try_execute(move || {
+            if let Err(e) = handle_connection(stream, &router, &options, &shutting_down, &streams) {
+                eprintln!("Encountered error writing response: {e}");
+            }
+        });
+
+        if let (Err(e), Ok(stream)) = (job, overflow.as_mut()) {
+            eprintln!("Turning away connection: {e}");
+
+            let _ = service_unavailable().write_to(stream);
+        }
+    }
+
+    println!("Shutting down, press Ctrl-C again to stop right away");
+
+    drop(listener);
+
+    if let Err(e) = rooms.close("Server shutting down") {
+        eprintln!("Failed to flush the message logs: {e}");
+    }
+
+    let deadline = Instant::now() + options.shutdown_timeout;
+
+    let busy = pool.shutdown(options.shutdown_timeout)
+        + streams.shutdown(deadline.saturating_duration_since(Instant::now()));
+
+    if busy > 0 {
+        eprintln!("Gave up waiting on {busy} connections");
+    }
+
+    println!("Server stopped");
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use smoll_chat::store::SyncPolicy;
+
+    fn get(router: &Router, resource: &str, cookie: Option<&str>) -> HttpResponse {
+        let cookie = cookie.map_or(String::new(), |c| {
+            format!("Cookie: {SESSION_COOKIE}={c}\r\n")
+        });
+
+        let mut request = HttpRequest::parse(&format!(
+            "GET {resource} HTTP/1.1\r\nHost: localhost\r\n{cookie}\r\n"
+        ))
+        .unwrap();
+
+        router.route(&mut request)
+    }
+
+    fn args(args: &str) -> Result<SmollChatOpts, OptionError> {
+        SmollChatOpts::parse_args(args.split_whitespace().map(str::to_string))
+    }
+
+    #[test]
+    fn test_args_and_env_file_share_options() {
+        let from_args = args("-p 9000 --workers 4 --fsync always --max-log-age 0").unwrap();
+        let from_env =
+            SmollChatOpts::parse_env("# A comment\nport=9000\n\nworkers = 4\nfsync=always\n")
+                .unwrap();
+
+        for opts in [from_args, from_env] {
+            assert_eq!(9000, opts.port);
+            assert_eq!(4, opts.workers);
+            assert_eq!(SyncPolicy::Always, opts.store_options.sync);
+            assert_eq!(None, opts.store_options.max_age);
+        }
+    }
+
+    #[test]
+    fn test_invalid_options() {
+        let error = |result: Result<SmollChatOpts, OptionError>| result.err().unwrap().to_string();
+
+        assert_eq!(
+            "Invalid workers 0, expected a number of workers above 0",
+            error(args("--workers 0"))
+        );
+        assert_eq!(
+            "Invalid port abc, expected a port number",
+            error(SmollChatOpts::parse_env("port=abc"))
+        );
+        assert_eq!("No value given for port", error(args("--port")));
+        assert_eq!(
+            "No value given for port",
+            error(SmollChatOpts::parse_env("port"))
+        );
+        assert_eq!("Unknown option colour", error(args("--colour red")));
+        assert_eq!("Unknown option 9000", error(args("9000")));
+    }
+
+    #[test]
+    fn test_history_needs_a_session() {
+        let sessions = Arc::new(Sessions::new(Duration::from_secs(60)));
+
+        let router = build_router(
+            &SmollChatOpts::default(),
+            "localhost:8080",
+            Arc::new(Rooms::new("Room", 10)),
+            Arc::clone(&sessions),
+            None,
+        )
+        .unwrap();
+
+        let (token, _) = sessions.create("asd");
 
-            inc.write(response.build().to_string().as_bytes()).unwrap();
+        for resource in ["/rooms/Room/history", "/history"] {
+            assert_eq!(401, get(&router, resource, None).status_code);
+            assert_eq!(401, get(&router, resource, Some("unknown")).status_code);
+            assert_eq!(200, get(&router, resource, Some(&token)).status_code);
         }
     }
 }
@@ -0,0 +1,241 @@
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::str::FromStr;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use crate::error::{InvalidPinSetting, PinError};
+
+/// How many digits go into a generated PIN.
+const GENERATED_DIGITS: usize = 6;
+
+/// Whether users need a PIN to join, and where it comes from.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PinSetting {
+    Off,
+    /// A new PIN every time the server starts.
+    Random,
+    Fixed(String),
+}
+
+/// Parses `off`, `random` or the digits of a fixed PIN.
+impl FromStr for PinSetting {
+    type Err = InvalidPinSetting;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "off" => Ok(Self::Off),
+            "random" => Ok(Self::Random),
+            _ if (4..=12).contains(&s.len()) && s.bytes().all(|b| b.is_ascii_digit()) => {
+                Ok(Self::Fixed(s.to_string()))
+            }
+            _ => Err(InvalidPinSetting(s.to_string())),
+        }
+    }
+}
+
+/// Wrong guesses made from one address in a row.
+#[derive(Debug)]
+struct Failures {
+    count: u32,
+    last_failure: Instant,
+}
+
+/// The PIN users enter to join the chat. An address that gets it wrong too
+/// many times in a row is locked out for a while, so the PIN cannot be
+/// guessed by trying every number.
+pub struct JoinPin {
+    pin: String,
+    max_attempts: u32,
+    lockout: Duration,
+    failures: Mutex<HashMap<IpAddr, Failures>>,
+}
+
+fn random_digits(count: usize) -> String {
+    let mut digits = String::with_capacity(count);
+
+    while digits.len() < count {
+        let mut bytes = [0; 16];
+
+        getrandom::getrandom(&mut bytes).expect("The OS failed to provide random bytes");
+
+        // Bytes past the last whole multiple of ten would favour low digits
+        for b in bytes.into_iter().filter(|b| *b < 250) {
+            if digits.len() < count {
+                digits.push(char::from(b'0' + b % 10));
+            }
+        }
+    }
+
+    digits
+}
+
+/// Compares in time that does not depend on where the input first differs.
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+impl JoinPin {
+    pub fn new(pin: &str, max_attempts: u32, lockout: Duration) -> Self {
+        Self {
+            pin: pin.to_string(),
+            max_attempts: max_attempts.max(1),
+            lockout,
+            failures: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Returns the PIN the setting calls for, if any.
+    pub fn from_setting(
+        setting: &PinSetting,
+        max_attempts: u32,
+        lockout: Duration,
+    ) -> Option<Self> {
+        match setting {
+            PinSetting::Off => None,
+            PinSetting::Random => Some(Self::new(
+                &random_digits(GENERATED_DIGITS),
+                max_attempts,
+                lockout,
+            )),
+            PinSetting::Fixed(pin) => Some(Self::new(pin, max_attempts, lockout)),
+        }
+    }
+
+    pub fn pin(&self) -> &str {
+        &self.pin
+    }
+
+    /// Checks the PIN entered from the address, counting it against the
+    /// address if it is wrong.
+    pub fn check(&self, addr: IpAddr, entered: &str) -> Result<(), PinError> {
+        let mut failures = self.failures.lock().unwrap();
+
+        let now = Instant::now();
+
+        // Forget addresses that have not guessed wrong for as long as a
+        // lockout lasts, which is also when their lockout runs out
+        failures.retain(|_, f| f.last_failure + self.lockout > now);
+
+        if let Some(f) = failures.get(&addr).filter(|f| f.count >= self.max_attempts) {
+            return Err(PinError::LockedOut(f.last_failure + self.lockout - now));
+        }
+
+        if constant_time_eq(entered.trim().as_bytes(), self.pin.as_bytes()) {
+            failures.remove(&addr);
+
+            return Ok(());
+        }
+
+        let entry = failures.entry(addr).or_insert(Failures {
+            count: 0,
+            last_failure: now,
+        });
+
+        entry.count += 1;
+        entry.last_failure = now;
+
+        if entry.count >= self.max_attempts {
+            return Err(PinError::LockedOut(self.lockout));
+        }
+
+        Err(PinError::Incorrect {
+            attempts_left: self.max_attempts - entry.count,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::Ipv4Addr;
+    use std::thread;
+
+    const ALICE: IpAddr = IpAddr::V4(Ipv4Addr::new(192, 168, 1, 2));
+    const BOB: IpAddr = IpAddr::V4(Ipv4Addr::new(192, 168, 1, 3));
+
+    #[test]
+    fn test_parse_pin_setting() {
+        assert_eq!(Ok(PinSetting::Off), "off".parse());
+        assert_eq!(Ok(PinSetting::Random), "random".parse());
+        assert_eq!(Ok(PinSetting::Fixed("0042".to_string())), "0042".parse());
+
+        for invalid in ["", "123", "12a4", "1234567890123"] {
+            assert_eq!(
+                Err(InvalidPinSetting(invalid.to_string())),
+                invalid.parse::<PinSetting>()
+            );
+        }
+    }
+
+    #[test]
+    fn test_random_pin() {
+        let pin = JoinPin::from_setting(&PinSetting::Random, 5, Duration::from_secs(60)).unwrap();
+
+        assert_eq!(GENERATED_DIGITS, pin.pin().len());
+        assert!(pin.pin().bytes().all(|b| b.is_ascii_digit()));
+
+        assert!(JoinPin::from_setting(&PinSetting::Off, 5, Duration::from_secs(60)).is_none());
+    }
+
+    #[test]
+    fn test_check_pin() {
+        let pin = JoinPin::new("1234", 3, Duration::from_secs(60));
+
+        assert_eq!(Ok(()), pin.check(ALICE, "1234"));
+        assert_eq!(Ok(()), pin.check(ALICE, " 1234\n"));
+        assert_eq!(
+            Err(PinError::Incorrect { attempts_left: 2 }),
+            pin.check(ALICE, "4321")
+        );
+
+        // Getting it right starts the count over
+        assert_eq!(Ok(()), pin.check(ALICE, "1234"));
+        assert_eq!(
+            Err(PinError::Incorrect { attempts_left: 2 }),
+            pin.check(ALICE, "")
+        );
+    }
+
+    #[test]
+    fn test_lockout_per_address() {
+        let pin = JoinPin::new("1234", 2, Duration::from_millis(50));
+
+        assert!(matches!(
+            pin.check(ALICE, "0000"),
+            Err(PinError::Incorrect { .. })
+        ));
+        assert!(matches!(
+            pin.check(ALICE, "0001"),
+            Err(PinError::LockedOut(_))
+        ));
+
+        // Even the right PIN is turned away until the lockout ends
+        assert!(matches!(
+            pin.check(ALICE, "1234"),
+            Err(PinError::LockedOut(_))
+        ));
+        assert_eq!(Ok(()), pin.check(BOB, "1234"));
+
+        thread::sleep(Duration::from_millis(60));
+
+        assert_eq!(Ok(()), pin.check(ALICE, "1234"));
+    }
+
+    #[test]
+    fn test_old_failures_are_forgotten() {
+        let pin = JoinPin::new("1234", 3, Duration::from_millis(50));
+
+        assert!(pin.check(ALICE, "0000").is_err());
+        assert!(pin.check(BOB, "0000").is_err());
+
+        thread::sleep(Duration::from_millis(60));
+
+        // Neither address was locked out, yet both are gone
+        assert_eq!(
+            Err(PinError::Incorrect { attempts_left: 2 }),
+            pin.check(ALICE, "0001")
+        );
+        assert_eq!(1, pin.failures.lock().unwrap().len());
+    }
+}
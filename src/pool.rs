@@ -0,0 +1,299 @@
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc::{self, Receiver, SyncSender, TrySendError};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+use crate::error::PoolBusy;
+
+type Job = Box<dyn FnOnce() + Send + 'static>;
+
+/// How often `shutdown` checks whether the workers are done.
+const SHUTDOWN_POLL_INTERVAL: Duration = Duration::from_millis(50);
+
+/// A fixed number of worker threads fed from a bounded queue. Once every
+/// worker is busy and the queue is full, new jobs are turned away instead of
+/// piling up.
+pub struct ThreadPool {
+    workers: Vec<JoinHandle<()>>,
+    sender: Option<SyncSender<Job>>,
+}
+
+impl ThreadPool {
+    /// Creates a pool of `size` workers that queues up to `backlog` jobs
+    /// while they are all busy.
+    pub fn new(size: usize, backlog: usize) -> Self {
+        assert!(size > 0, "Thread pool needs at least one worker");
+
+        let (sender, receiver) = mpsc::sync_channel::<Job>(backlog);
+        let receiver = Arc::new(Mutex::new(receiver));
+
+        let workers = (0..size)
+            .map(|_| {
+                let receiver = Arc::clone(&receiver);
+
+                thread::spawn(move || Self::work(&receiver))
+            })
+            .collect();
+
+        Self {
+            workers,
+            sender: Some(sender),
+        }
+    }
+
+    fn work(receiver: &Mutex<Receiver<Job>>) {
+        loop {
+            let job = receiver.lock().unwrap().recv();
+
+            match job {
+                // A panicking job should not take its worker down with it
+                Ok(job) => {
+                    if panic::catch_unwind(AssertUnwindSafe(job)).is_err() {
+                        eprintln!("Worker recovered from a panicking job");
+                    }
+                }
+                Err(_) => return,
+            }
+        }
+    }
+
+    pub fn size(&self) -> usize {
+        self.workers.len()
+    }
+
+    /// Stops taking jobs and waits up to `timeout` for the workers to finish
+    /// the ones already queued, returning how many were still busy when it
+    /// ran out. Those are left to be killed when the process exits.
+    pub fn shutdown(mut self, timeout: Duration) -> usize {
+        drop(self.sender.take());
+
+        let deadline = Instant::now() + timeout;
+
+        while Instant::now() < deadline && !self.workers.iter().all(JoinHandle::is_finished) {
+            thread::sleep(SHUTDOWN_POLL_INTERVAL);
+        }
+
+        let (finished, busy): (Vec<_>, Vec<_>) =
+            self.workers.drain(..).partition(JoinHandle::is_finished);
+
+        for worker in finished {
+            let _ = worker.join();
+        }
+
+        busy.len()
+    }
+
+    pub fn try_execute<F>(&self, job: F) -> Result<(), PoolBusy>
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        match self.sender.as_ref().unwrap().try_send(Box::new(job)) {
+            Ok(()) => Ok(()),
+            Err(TrySendError::Full(_)) | Err(TrySendError::Disconnected(_)) => Err(PoolBusy),
+        }
+    }
+}
+
+// Dropping the pool lets the workers finish the jobs already queued before
+// they are joined.
+impl Drop for ThreadPool {
+    fn drop(&mut self) {
+        drop(self.sender.take());
+
+        for worker in self.workers.drain(..) {
+            let _ = worker.join();
+        }
+    }
+}
+
+/// Runs jobs that last as long as a client stays connected, like event
+/// streams and WebSockets, on threads of their own. Only so many run at once,
+/// but unlike a `ThreadPool` they never hold up a worker other jobs wait for.
+pub struct BoundedSpawner {
+    limit: usize,
+    running: Arc<AtomicUsize>,
+}
+
+/// Counts its job as finished when dropped, even if the job panicked.
+struct Running(Arc<AtomicUsize>);
+
+impl Drop for Running {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+impl BoundedSpawner {
+    pub fn new(limit: usize) -> Self {
+        Self {
+            limit,
+            running: Arc::new(AtomicUsize::new(0)),
+        }
+    }
+
+    pub fn running(&self) -> usize {
+        self.running.load(Ordering::SeqCst)
+    }
+
+    /// Starts the job on a new thread, unless `limit` jobs are running.
+    pub fn try_spawn<F>(&self, job: F) -> Result<(), PoolBusy>
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        let reserved = self
+            .running
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |running| {
+                (running < self.limit).then_some(running + 1)
+            });
+
+        if reserved.is_err() {
+            return Err(PoolBusy);
+        }
+
+        let running = Running(Arc::clone(&self.running));
+
+        thread::spawn(move || {
+            let _running = running;
+
+            if panic::catch_unwind(AssertUnwindSafe(job)).is_err() {
+                eprintln!("Recovered from a panicking connection");
+            }
+        });
+
+        Ok(())
+    }
+
+    /// Waits up to `timeout` for the running jobs to finish, returning how
+    /// many were still running when it ran out.
+    pub fn shutdown(&self, timeout: Duration) -> usize {
+        let deadline = Instant::now() + timeout;
+
+        while Instant::now() < deadline && self.running() > 0 {
+            thread::sleep(SHUTDOWN_POLL_INTERVAL);
+        }
+
+        self.running()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Barrier;
+
+    #[test]
+    fn test_pool_runs_jobs() {
+        let counter = Arc::new(AtomicUsize::new(0));
+
+        {
+            let pool = ThreadPool::new(4, 16);
+
+            for _ in 0..16 {
+                let counter = Arc::clone(&counter);
+
+                pool.try_execute(move || {
+                    counter.fetch_add(1, Ordering::SeqCst);
+                })
+                .unwrap();
+            }
+        }
+
+        assert_eq!(16, counter.load(Ordering::SeqCst));
+    }
+
+    #[test]
+    fn test_pool_rejects_when_full() {
+        let pool = ThreadPool::new(1, 1);
+        let started = Arc::new(Barrier::new(2));
+        let release = Arc::new(Barrier::new(2));
+
+        let (job_started, job_release) = (Arc::clone(&started), Arc::clone(&release));
+
+        pool.try_execute(move || {
+            job_started.wait();
+            job_release.wait();
+        })
+        .unwrap();
+
+        started.wait();
+
+        assert!(pool.try_execute(|| ()).is_ok());
+        assert!(pool.try_execute(|| ()).is_err());
+
+        release.wait();
+    }
+
+    #[test]
+    fn test_shutdown_waits_for_jobs() {
+        let counter = Arc::new(AtomicUsize::new(0));
+
+        let pool = ThreadPool::new(2, 4);
+
+        for _ in 0..4 {
+            let counter = Arc::clone(&counter);
+
+            pool.try_execute(move || {
+                thread::sleep(Duration::from_millis(20));
+                counter.fetch_add(1, Ordering::SeqCst);
+            })
+            .unwrap();
+        }
+
+        assert_eq!(0, pool.shutdown(Duration::from_secs(5)));
+        assert_eq!(4, counter.load(Ordering::SeqCst));
+    }
+
+    #[test]
+    fn test_shutdown_gives_up_on_stuck_jobs() {
+        let pool = ThreadPool::new(2, 2);
+        let (sender, receiver) = mpsc::channel::<()>();
+
+        pool.try_execute(move || {
+            let _ = receiver.recv();
+        })
+        .unwrap();
+
+        assert_eq!(1, pool.shutdown(Duration::from_millis(100)));
+
+        drop(sender);
+    }
+
+    #[test]
+    fn test_pool_survives_panicking_job() {
+        let pool = ThreadPool::new(1, 2);
+        let (sender, receiver) = mpsc::channel();
+
+        pool.try_execute(|| panic!("job failed")).unwrap();
+        pool.try_execute(move || sender.send(()).unwrap()).unwrap();
+
+        assert!(receiver.recv().is_ok());
+    }
+
+    #[test]
+    fn test_spawner_limits_running_jobs() {
+        let spawner = BoundedSpawner::new(2);
+        let (sender, receiver) = mpsc::channel::<()>();
+        let receiver = Arc::new(Mutex::new(receiver));
+
+        for _ in 0..2 {
+            let receiver = Arc::clone(&receiver);
+
+            spawner
+                .try_spawn(move || {
+                    let _ = receiver.lock().unwrap().recv();
+                })
+                .unwrap();
+        }
+
+        assert_eq!(2, spawner.running());
+        assert!(spawner.try_spawn(|| ()).is_err());
+
+        drop(sender);
+
+        assert_eq!(0, spawner.shutdown(Duration::from_secs(5)));
+        assert!(spawner.try_spawn(|| panic!("job failed")).is_ok());
+        assert_eq!(0, spawner.shutdown(Duration::from_secs(5)));
+    }
+}
@@ -0,0 +1,326 @@
+use std::collections::BTreeMap;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, RwLock};
+
+use crate::chat::ChatState;
+use crate::error::RoomError;
+use crate::http::percent_decode;
+use crate::store::{MessageStore, StoreOptions};
+
+const MAX_ROOM_NAME_LENGTH: usize = 64;
+
+/// How many rooms there can be unless the server is told otherwise.
+pub const DEFAULT_MAX_ROOMS: usize = 100;
+
+/// Checks that the name can be shown on a page and used in a path segment,
+/// refusing whatever the router would not decode back out of one.
+fn validate_name(name: &str) -> Result<(), RoomError> {
+    let valid = !name.trim().is_empty()
+        && name.chars().count() <= MAX_ROOM_NAME_LENGTH
+        && !matches!(name, "." | "..")
+        && !name
+            .chars()
+            .any(|c| c.is_control() || c == '/' || c == '\\');
+
+    match valid {
+        true => Ok(()),
+        false => Err(RoomError::InvalidName(name.to_string())),
+    }
+}
+
+/// The rooms hosted by the server, each with its own messages and members.
+/// There is always a default room, and when a data directory is given every
+/// room's messages are logged to it and the rooms found there are reopened.
+pub struct Rooms {
+    rooms: RwLock<BTreeMap<String, Arc<ChatState>>>,
+    default_room: String,
+    history_size: usize,
+    max_rooms: usize,
+    store: Option<(PathBuf, StoreOptions)>,
+}
+
+impl Rooms {
+    /// Hosts the default room, keeping messages in memory only.
+    pub fn new(default_room: &str, history_size: usize) -> Self {
+        let rooms = Self {
+            rooms: RwLock::new(BTreeMap::new()),
+            default_room: default_room.to_string(),
+            history_size,
+            max_rooms: DEFAULT_MAX_ROOMS,
+            store: None,
+        };
+
+        rooms.insert(ChatState::with_history_size(default_room, history_size));
+
+        rooms
+    }
+
+    /// Hosts the default room along with every room logged to the data
+    /// directory, replaying their messages.
+    pub fn open(
+        default_room: &str,
+        history_size: usize,
+        data_dir: &Path,
+        options: StoreOptions,
+    ) -> io::Result<Self> {
+        let rooms = Self {
+            rooms: RwLock::new(BTreeMap::new()),
+            default_room: default_room.to_string(),
+            history_size,
+            max_rooms: DEFAULT_MAX_ROOMS,
+            store: Some((data_dir.to_path_buf(), options)),
+        };
+
+        fs::create_dir_all(data_dir)?;
+
+        let mut names = vec![default_room.to_string()];
+
+        for entry in fs::read_dir(data_dir)? {
+            let path = entry?.path();
+
+            let name = match path.file_name().and_then(|f| f.to_str()) {
+                Some(file_name) => match file_name.strip_suffix(".jsonl") {
+                    Some(encoded) => percent_decode(encoded),
+                    None => continue,
+                },
+                None => continue,
+            };
+
+            if validate_name(&name).is_ok() && !names.contains(&name) {
+                names.push(name);
+            }
+        }
+
+        for name in names {
+            rooms.insert(rooms.open_room(&name)?);
+        }
+
+        Ok(rooms)
+    }
+
+    /// Limits how many rooms can be created, counting the ones there already
+    /// are. Rooms reopened from the data directory are kept even past it.
+    pub fn max_rooms(mut self, max_rooms: usize) -> Self {
+        self.max_rooms = max_rooms;
+
+        self
+    }
+
+    fn open_room(&self, name: &str) -> io::Result<ChatState> {
+        let mut chat = ChatState::with_history_size(name, self.history_size);
+
+        if let Some((data_dir, options)) = self.store.as_ref() {
+            let options = StoreOptions {
+                keep: self.history_size,
+                ..*options
+            };
+
+            let (store, replayed) = MessageStore::open(data_dir, name, options)?;
+
+            chat.persist(store, replayed);
+        }
+
+        Ok(chat)
+    }
+
+    fn insert(&self, chat: ChatState) -> Arc<ChatState> {
+        let chat = Arc::new(chat);
+
+        self.rooms
+            .write()
+            .unwrap()
+            .insert(chat.room().to_string(), Arc::clone(&chat));
+
+        chat
+    }
+
+    pub fn default_room(&self) -> Arc<ChatState> {
+        self.get(&self.default_room)
+            .expect("The default room is never removed")
+    }
+
+    pub fn get(&self, name: &str) -> Option<Arc<ChatState>> {
+        self.rooms.read().unwrap().get(name).cloned()
+    }
+
+    /// Returns every room, ordered by name.
+    pub fn list(&self) -> Vec<Arc<ChatState>> {
+        self.rooms.read().unwrap().values().cloned().collect()
+    }
+
+    /// Closes every room with the notice, flushing all of their logs even if
+    /// some fail, and returns the first error.
+    pub fn close(&self, notice: &str) -> io::Result<()> {
+        let mut result = Ok(());
+
+        for chat in self.list() {
+            let closed = chat.close(notice);
+
+            result = result.and(closed);
+        }
+
+        result
+    }
+
+    pub fn create(&self, name: &str) -> Result<Arc<ChatState>, RoomError> {
+        validate_name(name)?;
+
+        self.check_room_for(name, &self.rooms.read().unwrap())?;
+
+        // Opened before taking the lock so that reading the room's log does
+        // not hold up every other room, then checked again in case the same
+        // room was created meanwhile
+        let chat = Arc::new(self.open_room(name)?);
+
+        let mut rooms = self.rooms.write().unwrap();
+
+        self.check_room_for(name, &rooms)?;
+
+        rooms.insert(name.to_string(), Arc::clone(&chat));
+
+        Ok(chat)
+    }
+
+    fn check_room_for(
+        &self,
+        name: &str,
+        rooms: &BTreeMap<String, Arc<ChatState>>,
+    ) -> Result<(), RoomError> {
+        if rooms.contains_key(name) {
+            return Err(RoomError::AlreadyExists(name.to_string()));
+        }
+
+        if rooms.len() >= self.max_rooms {
+            return Err(RoomError::TooMany(self.max_rooms));
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::chat::{ChatEvent, UserMessage};
+    use crate::http::{percent_encode, HttpRequest, HttpResponse};
+    use crate::router::Router;
+    use crate::test_support::TempDir;
+
+    fn names(rooms: &Rooms) -> Vec<String> {
+        rooms.list().iter().map(|r| r.room().to_string()).collect()
+    }
+
+    #[test]
+    fn test_default_room_exists() {
+        let rooms = Rooms::new("Room", 10);
+
+        assert_eq!("Room", rooms.default_room().room());
+        assert_eq!(vec!["Room"], names(&rooms));
+        assert!(rooms.get("Other").is_none());
+    }
+
+    #[test]
+    fn test_create_rooms() {
+        let rooms = Rooms::new("Room", 10);
+
+        let games = rooms.create("Game Night").unwrap();
+
+        assert!(Arc::ptr_eq(&games, &rooms.get("Game Night").unwrap()));
+        assert_eq!(vec!["Game Night", "Room"], names(&rooms));
+
+        assert!(matches!(
+            rooms.create("Room"),
+            Err(RoomError::AlreadyExists(_))
+        ));
+
+        for name in ["", "   ", "a/b", "a\\b", "..", "tab\there", &"x".repeat(65)] {
+            assert!(matches!(rooms.create(name), Err(RoomError::InvalidName(_))));
+        }
+    }
+
+    #[test]
+    fn test_created_names_route_back_to_the_room() {
+        let rooms = Rooms::new("Room", 10);
+
+        let mut router = Router::new();
+
+        router
+            .get("/rooms/:room", |request| {
+                HttpResponse::builder()
+                    .status_code(200)
+                    .body(request.param("room").unwrap())
+                    .build()
+            })
+            .unwrap();
+
+        for name in ["Game Night", "50% off", "a?b#c", "naïve", "..."] {
+            rooms.create(name).unwrap();
+
+            let mut request = HttpRequest::parse(&format!(
+                "GET /rooms/{} HTTP/1.1\r\nHost: localhost\r\n\r\n",
+                percent_encode(name)
+            ))
+            .unwrap();
+
+            let response = router.route(&mut request);
+
+            assert_eq!(200, response.status_code);
+            assert!(response.to_string().ends_with(name));
+        }
+    }
+
+    #[test]
+    fn test_create_up_to_max_rooms() {
+        let rooms = Rooms::new("Room", 10).max_rooms(2);
+
+        rooms.create("Games").unwrap();
+
+        assert!(matches!(rooms.create("Music"), Err(RoomError::TooMany(2))));
+        assert!(matches!(
+            rooms.create("Games"),
+            Err(RoomError::AlreadyExists(_))
+        ));
+        assert_eq!(vec!["Games", "Room"], names(&rooms));
+    }
+
+    #[test]
+    fn test_rooms_are_separate() {
+        let rooms = Rooms::new("Room", 10);
+        let games = rooms.create("Games").unwrap();
+
+        games.publish(ChatEvent::Message(UserMessage::new(
+            "asd".to_string(),
+            "hello".to_string(),
+        )));
+
+        assert_eq!(1, games.cursor());
+        assert_eq!(0, rooms.default_room().cursor());
+    }
+
+    #[test]
+    fn test_reopen_logged_rooms() {
+        let dir = TempDir::new("rooms");
+
+        let rooms = Rooms::open("Room", 10, dir.path(), StoreOptions::default()).unwrap();
+
+        rooms
+            .create("Game Night")
+            .unwrap()
+            .publish(ChatEvent::Message(UserMessage::new(
+                "asd".to_string(),
+                "hello".to_string(),
+            )));
+
+        drop(rooms);
+
+        let rooms = Rooms::open("Lobby", 10, dir.path(), StoreOptions::default()).unwrap();
+
+        assert_eq!(vec!["Game Night", "Lobby", "Room"], names(&rooms));
+        assert_eq!(
+            1,
+            rooms.get("Game Night").unwrap().history(None, 10).0.len()
+        );
+    }
+}
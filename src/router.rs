@@ -0,0 +1,389 @@
+use std::collections::HashMap;
+
+use crate::error::RouteAlreadyRegistered;
+use crate::http::{percent_decode, HttpRequest, HttpResponse};
+
+pub type Handler = Box<dyn Fn(&HttpRequest) -> HttpResponse + Send + Sync>;
+
+/// A single piece of a route pattern. Patterns are split on `/`, where a
+/// segment starting with `:` captures exactly one path segment and a segment
+/// starting with `*` captures the rest of the path.
+#[derive(Debug, Clone)]
+enum Segment {
+    Literal(String),
+    Param(String),
+    Wildcard(String),
+}
+
+impl Segment {
+    fn parse(segment: &str) -> Self {
+        if let Some(name) = segment.strip_prefix(':') {
+            Self::Param(name.to_string())
+        } else if let Some(name) = segment.strip_prefix('*') {
+            Self::Wildcard(name.to_string())
+        } else {
+            Self::Literal(segment.to_string())
+        }
+    }
+
+    /// Literal segments take precedence over parameters, which take
+    /// precedence over wildcards.
+    fn rank(&self) -> u8 {
+        match self {
+            Self::Literal(_) => 2,
+            Self::Param(_) => 1,
+            Self::Wildcard(_) => 0,
+        }
+    }
+}
+
+// Two patterns are the same route if they match the same paths, regardless of
+// what their parameters are named.
+impl PartialEq for Segment {
+    fn eq(&self, other: &Self) -> bool {
+        match (self, other) {
+            (Self::Literal(a), Self::Literal(b)) => a == b,
+            (Self::Param(_), Self::Param(_)) => true,
+            (Self::Wildcard(_), Self::Wildcard(_)) => true,
+            _ => false,
+        }
+    }
+}
+
+fn split_path(path: &str) -> impl Iterator<Item = &str> {
+    path.strip_prefix('/').unwrap_or(path).split('/')
+}
+
+/// Decodes a segment of the path, refusing ones that would let it climb out
+/// of wherever a handler joins it onto once decoded.
+fn decode_segment(piece: &str) -> Option<String> {
+    let decoded = percent_decode(piece);
+
+    match decoded == "." || decoded == ".." || decoded.contains(['/', '\\', '\0']) {
+        true => None,
+        false => Some(decoded),
+    }
+}
+
+struct Route {
+    method: String,
+    pattern: Vec<Segment>,
+    handler: Handler,
+}
+
+impl Route {
+    /// Matches the path against the route's pattern, returning the captured
+    /// parameters on success.
+    fn matches(&self, path: &str) -> Option<HashMap<String, String>> {
+        let mut params = HashMap::new();
+        let mut pieces = split_path(path);
+
+        for segment in self.pattern.iter() {
+            match segment {
+                Segment::Literal(literal) => {
+                    if pieces.next()? != literal {
+                        return None;
+                    }
+                }
+                Segment::Param(name) => {
+                    let piece = pieces.next().filter(|p| !p.is_empty())?;
+
+                    params.insert(name.clone(), decode_segment(piece)?);
+                }
+                Segment::Wildcard(name) => {
+                    let rest = pieces
+                        .map(decode_segment)
+                        .collect::<Option<Vec<String>>>()?
+                        .join("/");
+
+                    if rest.is_empty() {
+                        return None;
+                    }
+
+                    params.insert(name.clone(), rest);
+
+                    return Some(params);
+                }
+            }
+        }
+
+        match pieces.next() {
+            Some(_) => None,
+            None => Some(params),
+        }
+    }
+
+    fn specificity(&self) -> Vec<u8> {
+        self.pattern.iter().map(Segment::rank).collect()
+    }
+}
+
+#[derive(Default)]
+pub struct Router {
+    routes: Vec<Route>,
+}
+
+impl Router {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Registers a handler for the method and path pattern, e.g.
+    /// `/rooms/:room/messages` or `/static/*path`. Captured segments are
+    /// available to the handler through `HttpRequest::param`.
+    pub fn register<F>(
+        &mut self,
+        method: &str,
+        path: &str,
+        handler: F,
+    ) -> Result<(), RouteAlreadyRegistered>
+    where
+        F: Fn(&HttpRequest) -> HttpResponse + Send + Sync + 'static,
+    {
+        let pattern: Vec<Segment> = split_path(path).map(Segment::parse).collect();
+
+        if self
+            .routes
+            .iter()
+            .any(|r| r.method == method && r.pattern == pattern)
+        {
+            return Err(RouteAlreadyRegistered);
+        }
+
+        self.routes.push(Route {
+            method: method.to_string(),
+            pattern,
+            handler: Box::new(handler),
+        });
+
+        Ok(())
+    }
+
+    pub fn get<F>(&mut self, path: &str, handler: F) -> Result<(), RouteAlreadyRegistered>
+    where
+        F: Fn(&HttpRequest) -> HttpResponse + Send + Sync + 'static,
+    {
+        self.register("GET", path, handler)
+    }
+
+    pub fn post<F>(&mut self, path: &str, handler: F) -> Result<(), RouteAlreadyRegistered>
+    where
+        F: Fn(&HttpRequest) -> HttpResponse + Send + Sync + 'static,
+    {
+        self.register("POST", path, handler)
+    }
+
+    /// Dispatches the request to the most specific handler registered for its
+    /// method and resource, falling back to a 404 response if there is none.
+    /// Parameters captured from the path are stored on the request.
+    pub fn route(&self, request: &mut HttpRequest) -> HttpResponse {
+        let found = self
+            .routes
+            .iter()
+            .filter(|r| r.method == request.method)
+            .filter_map(|r| r.matches(&request.resource).map(|params| (r, params)))
+            .max_by(|(a, _), (b, _)| a.specificity().cmp(&b.specificity()));
+
+        match found {
+            Some((route, params)) => {
+                request.set_params(params);
+
+                (route.handler)(request)
+            }
+            None => HttpResponse::not_found(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn request(method: &str, resource: &str) -> HttpRequest {
+        HttpRequest::parse(&format!(
+            "{method} {resource} HTTP/1.1\r\nHost: localhost\r\n\r\n"
+        ))
+        .unwrap()
+    }
+
+    fn request_for(resource: &str) -> HttpRequest {
+        request("GET", resource)
+    }
+
+    fn ok(body: &'static str) -> impl Fn(&HttpRequest) -> HttpResponse {
+        move |_| {
+            HttpResponse::builder()
+                .status_code(200)
+                .status_message("OK")
+                .body(body)
+                .build()
+        }
+    }
+
+    #[test]
+    fn test_route_to_registered_handler() {
+        let mut router = Router::new();
+
+        router.get("/", ok("index")).unwrap();
+        router.get("/chat", ok("chat")).unwrap();
+
+        let response = router.route(&mut request("GET", "/chat"));
+
+        assert_eq!(200, response.status_code);
+        assert!(response.to_string().ends_with("chat"));
+    }
+
+    #[test]
+    fn test_route_matches_method() {
+        let mut router = Router::new();
+
+        router.get("/login", ok("form")).unwrap();
+        router.post("/login", ok("login")).unwrap();
+
+        let response = router.route(&mut request("POST", "/login"));
+
+        assert!(response.to_string().ends_with("login"));
+    }
+
+    #[test]
+    fn test_route_not_found() {
+        let mut router = Router::new();
+
+        router.get("/", ok("index")).unwrap();
+
+        assert_eq!(
+            404,
+            router.route(&mut request("GET", "/missing")).status_code
+        );
+        assert_eq!(404, router.route(&mut request("DELETE", "/")).status_code);
+    }
+
+    #[test]
+    fn test_route_path_parameters() {
+        let mut router = Router::new();
+
+        router
+            .get("/rooms/:room/messages", |request| {
+                HttpResponse::builder()
+                    .status_code(200)
+                    .status_message("OK")
+                    .body(request.param("room").unwrap())
+                    .build()
+            })
+            .unwrap();
+
+        let mut request = request("GET", "/rooms/lobby/messages");
+        let response = router.route(&mut request);
+
+        assert!(response.to_string().ends_with("lobby"));
+        assert_eq!(Some("lobby"), request.param("room"));
+
+        let mut request = request_for("/rooms/Game%20Night/messages");
+        router.route(&mut request);
+
+        assert_eq!(Some("Game Night"), request.param("room"));
+
+        assert_eq!(
+            404,
+            router
+                .route(&mut request_for("/rooms//messages"))
+                .status_code
+        );
+        assert_eq!(
+            404,
+            router.route(&mut request_for("/rooms/lobby")).status_code
+        );
+        assert_eq!(
+            404,
+            router
+                .route(&mut request_for("/rooms/lobby/messages/old"))
+                .status_code
+        );
+    }
+
+    #[test]
+    fn test_route_wildcard() {
+        let mut router = Router::new();
+
+        router
+            .get("/static/*path", |request| {
+                HttpResponse::builder()
+                    .status_code(200)
+                    .status_message("OK")
+                    .body(request.param("path").unwrap())
+                    .build()
+            })
+            .unwrap();
+
+        let response = router.route(&mut request_for("/static/css/chat.css"));
+
+        assert!(response.to_string().ends_with("css/chat.css"));
+        assert_eq!(404, router.route(&mut request_for("/static/")).status_code);
+
+        let response = router.route(&mut request_for("/static/my%20notes.txt"));
+
+        assert!(response.to_string().ends_with("my notes.txt"));
+    }
+
+    #[test]
+    fn test_route_rejects_traversal() {
+        let mut router = Router::new();
+
+        router.get("/static/*path", ok("file")).unwrap();
+        router.get("/rooms/:room", ok("room")).unwrap();
+
+        for path in [
+            "/static/../../etc/passwd",
+            "/static/%2e%2e/%2e%2e/etc/passwd",
+            "/static/css/%2E%2E/%2E%2E/secret",
+            "/static/..%2f..%2fetc%2fpasswd",
+            "/static/..%5c..%5csecret",
+            "/static/chat.js%00.css",
+            "/rooms/%2e%2e",
+            "/rooms/a%2fb",
+        ] {
+            assert_eq!(
+                404,
+                router.route(&mut request_for(path)).status_code,
+                "{path} was routed"
+            );
+        }
+    }
+
+    #[test]
+    fn test_route_prefers_most_specific() {
+        let mut router = Router::new();
+
+        router.get("/rooms/*rest", ok("wildcard")).unwrap();
+        router.get("/rooms/:room", ok("param")).unwrap();
+        router.get("/rooms/new", ok("literal")).unwrap();
+
+        let literal = router.route(&mut request_for("/rooms/new"));
+        let param = router.route(&mut request_for("/rooms/lobby"));
+        let wildcard = router.route(&mut request_for("/rooms/lobby/members"));
+
+        assert!(literal.to_string().ends_with("literal"));
+        assert!(param.to_string().ends_with("param"));
+        assert!(wildcard.to_string().ends_with("wildcard"));
+    }
+
+    #[test]
+    fn test_register_duplicate_pattern() {
+        let mut router = Router::new();
+
+        router.get("/rooms/:room", ok("room")).unwrap();
+
+        assert!(router.get("/rooms/:name", ok("other")).is_err());
+        assert!(router.get("/rooms/*rest", ok("rest")).is_ok());
+    }
+
+    #[test]
+    fn test_register_duplicate_route() {
+        let mut router = Router::new();
+
+        router.get("/", ok("index")).unwrap();
+
+        assert!(router.get("/", ok("other")).is_err());
+        assert!(router.post("/", ok("post")).is_ok());
+    }
+}
@@ -0,0 +1,200 @@
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// How many random bytes go into a session token.
+const TOKEN_BYTES: usize = 32;
+
+/// The user a session was issued to.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Session {
+    pub username: String,
+    last_seen: Instant,
+}
+
+/// Sessions issued to users when they log in, looked up by the random token
+/// their browser sends back. A session expires once it goes unused for
+/// longer than the timeout.
+///
+/// A username belongs to one session at a time, so nobody can pass
+/// themselves off as someone else in any room.
+pub struct Sessions {
+    sessions: Mutex<HashMap<String, Session>>,
+    timeout: Duration,
+}
+
+fn new_token() -> String {
+    let mut bytes = [0; TOKEN_BYTES];
+
+    getrandom::getrandom(&mut bytes).expect("The OS failed to provide random bytes");
+
+    bytes.iter().map(|b| format!("{b:02x}")).collect()
+}
+
+impl Sessions {
+    pub fn new(timeout: Duration) -> Self {
+        Self {
+            sessions: Mutex::new(HashMap::new()),
+            timeout,
+        }
+    }
+
+    pub fn timeout(&self) -> Duration {
+        self.timeout
+    }
+
+    /// Starts a session for the user, returning its token along with the
+    /// session. A username already taken gets a number added to it.
+    pub fn create(&self, username: &str) -> (String, Session) {
+        self.insert(&mut self.sessions.lock().unwrap(), username)
+    }
+
+    /// Starts a session for the user in place of the one the token belongs
+    /// to, so logging in again does not keep the old username taken.
+    pub fn replace(&self, token: &str, username: &str) -> (String, Session) {
+        let mut sessions = self.sessions.lock().unwrap();
+
+        sessions.remove(token);
+
+        self.insert(&mut sessions, username)
+    }
+
+    fn insert(&self, sessions: &mut HashMap<String, Session>, username: &str) -> (String, Session) {
+        // Nothing else clears out sessions that are never used again, or frees
+        // their usernames
+        sessions.retain(|_, s| s.last_seen.elapsed() < self.timeout);
+
+        let taken = |name: &str| {
+            sessions
+                .values()
+                .any(|s| s.username.to_lowercase() == name.to_lowercase())
+        };
+
+        let mut unique = username.to_string();
+
+        for n in 2.. {
+            if !taken(&unique) {
+                break;
+            }
+
+            unique = format!("{username} ({n})");
+        }
+
+        let token = new_token();
+
+        let session = Session {
+            username: unique,
+            last_seen: Instant::now(),
+        };
+
+        sessions.insert(token.clone(), session.clone());
+
+        (token, session)
+    }
+
+    /// Returns the session the token belongs to if it has not expired,
+    /// keeping it alive for another timeout.
+    pub fn get(&self, token: &str) -> Option<Session> {
+        let mut sessions = self.sessions.lock().unwrap();
+
+        let session = sessions.get_mut(token)?;
+
+        if session.last_seen.elapsed() >= self.timeout {
+            sessions.remove(token);
+
+            return None;
+        }
+
+        session.last_seen = Instant::now();
+
+        Some(session.clone())
+    }
+
+    /// Ends the session, returning it if it existed.
+    pub fn remove(&self, token: &str) -> Option<Session> {
+        self.sessions.lock().unwrap().remove(token)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread;
+
+    #[test]
+    fn test_sessions_map_tokens_to_users() {
+        let sessions = Sessions::new(Duration::from_secs(60));
+
+        let (asd, _) = sessions.create("asd");
+        let (qwe, _) = sessions.create("qwe");
+
+        assert_ne!(asd, qwe);
+        assert_eq!(TOKEN_BYTES * 2, asd.len());
+        assert!(asd.bytes().all(|b| b.is_ascii_hexdigit()));
+
+        assert_eq!("asd", sessions.get(&asd).unwrap().username);
+        assert_eq!("qwe", sessions.get(&qwe).unwrap().username);
+        assert!(sessions.get("not-a-token").is_none());
+    }
+
+    #[test]
+    fn test_remove_session() {
+        let sessions = Sessions::new(Duration::from_secs(60));
+
+        let (token, _) = sessions.create("asd");
+
+        assert_eq!("asd", sessions.remove(&token).unwrap().username);
+        assert!(sessions.get(&token).is_none());
+        assert!(sessions.remove(&token).is_none());
+    }
+
+    #[test]
+    fn test_usernames_are_unique() {
+        let sessions = Sessions::new(Duration::from_secs(60));
+
+        let (_, first) = sessions.create("asd");
+        let (token, second) = sessions.create("ASD");
+        let (_, third) = sessions.create("asd");
+
+        assert_eq!("asd", first.username);
+        assert_eq!("ASD (2)", second.username);
+        assert_eq!("asd (3)", third.username);
+
+        // Logging out frees the name up again
+        sessions.remove(&token);
+
+        assert_eq!("asd (2)", sessions.create("asd (2)").1.username);
+    }
+
+    #[test]
+    fn test_logging_in_again_keeps_the_username() {
+        let sessions = Sessions::new(Duration::from_secs(60));
+
+        let (first, _) = sessions.create("asd");
+        let (second, session) = sessions.replace(&first, "asd");
+
+        assert_eq!("asd", session.username);
+        assert!(sessions.get(&first).is_none());
+        assert!(sessions.get(&second).is_some());
+
+        // A token that was already replaced frees up nothing
+        assert_eq!("asd (2)", sessions.replace(&first, "asd").1.username);
+    }
+
+    #[test]
+    fn test_sessions_expire() {
+        let sessions = Sessions::new(Duration::from_millis(50));
+
+        let (token, _) = sessions.create("asd");
+
+        thread::sleep(Duration::from_millis(30));
+        assert!(sessions.get(&token).is_some());
+
+        // Using the session kept it alive
+        thread::sleep(Duration::from_millis(30));
+        assert!(sessions.get(&token).is_some());
+
+        thread::sleep(Duration::from_millis(60));
+        assert!(sessions.get(&token).is_none());
+    }
+}
@@ -0,0 +1,542 @@
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufRead, BufReader, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use crate::chat::UserMessage;
+use crate::error::InvalidSyncPolicy;
+use crate::http::percent_encode;
+use crate::json::Json;
+
+/// When appended messages are flushed from the OS's buffers to the disk.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SyncPolicy {
+    /// After every message, so none are lost if the machine goes down.
+    Always,
+    /// On the first message once this long has passed since the last sync.
+    Interval(Duration),
+    /// Whenever the OS gets to it.
+    Never,
+}
+
+/// Parses `always`, `never` or a number of seconds between syncs.
+impl FromStr for SyncPolicy {
+    type Err = InvalidSyncPolicy;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "always" => Ok(Self::Always),
+            "never" => Ok(Self::Never),
+            _ => s
+                .parse::<u64>()
+                .map(|secs| Self::Interval(Duration::from_secs(secs)))
+                .map_err(|_| InvalidSyncPolicy(s.to_string())),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct StoreOptions {
+    pub sync: SyncPolicy,
+    /// How many of the newest messages survive compacting a log that grew
+    /// too large.
+    pub keep: usize,
+    /// The log is compacted once it grows by this many bytes since it was
+    /// last compacted.
+    pub max_file_size: u64,
+    /// The log is compacted this often, dropping messages older than it.
+    pub max_age: Option<Duration>,
+}
+
+impl Default for StoreOptions {
+    fn default() -> Self {
+        Self {
+            sync: SyncPolicy::Interval(Duration::from_secs(1)),
+            keep: 100,
+            max_file_size: 8 * 1024 * 1024,
+            max_age: None,
+        }
+    }
+}
+
+/// An append-only log of a room's messages, stored as one JSON object per
+/// line, that is replayed when the room is opened. Once it grows too large it
+/// is compacted down to the newest messages, and once it gets too old the
+/// messages older than that are dropped.
+///
+/// Compacting rewrites the log in place rather than rotating it into numbered
+/// files, as only the newest messages are ever replayed. It starts the new
+/// log with the id of the newest message, so ids carry on from there even if
+/// no message was kept.
+pub struct MessageStore {
+    path: PathBuf,
+    file: File,
+    options: StoreOptions,
+    /// The id of the newest message ever written to the log.
+    last_id: u64,
+    size: u64,
+    /// The size right after the last compaction. The messages kept can take
+    /// up more than `max_file_size` on their own, so growth is measured from
+    /// here to avoid rewriting the log on every message.
+    compacted_size: u64,
+    last_sync: Instant,
+    dirty: bool,
+    compacted: Instant,
+}
+
+fn unix_millis(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_millis() as u64)
+}
+
+impl MessageStore {
+    /// Opens the room's log in the data directory, creating both if needed,
+    /// and returns it along with the messages it holds that are young enough
+    /// to keep, oldest first.
+    pub fn open(
+        data_dir: &Path,
+        room: &str,
+        options: StoreOptions,
+    ) -> io::Result<(Self, Vec<UserMessage>)> {
+        fs::create_dir_all(data_dir)?;
+
+        let path = data_dir.join(format!("{}.jsonl", percent_encode(room)));
+
+        let (mut messages, last_id) = Self::load(&path)?;
+
+        Self::drop_old(&mut messages, &options);
+
+        let mut file = OpenOptions::new().create(true).append(true).open(&path)?;
+
+        let mut size = file.metadata()?.len();
+
+        // Whatever was half written when the server last went down is ended,
+        // so it does not swallow the next message
+        if size > 0 && Self::last_byte(&path)? != b'\n' {
+            file.write_all(b"\n")?;
+
+            size += 1;
+        }
+
+        let store = Self {
+            last_id,
+            size,
+            // How much of the log is worth keeping is not known until it is
+            // compacted
+            compacted_size: 0,
+            path,
+            file,
+            options,
+            last_sync: Instant::now(),
+            dirty: false,
+            compacted: Instant::now(),
+        };
+
+        Ok((store, messages))
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// The id of the newest message ever written to the log, or 0.
+    pub fn last_id(&self) -> u64 {
+        self.last_id
+    }
+
+    /// Appends the message to the log, syncing and compacting it as the
+    /// options call for.
+    pub fn append(&mut self, message: &UserMessage) -> io::Result<()> {
+        let line = format!("{}\n", message.to_json());
+
+        self.file.write_all(line.as_bytes())?;
+
+        self.size += line.len() as u64;
+        self.last_id = self.last_id.max(message.id);
+        self.dirty = true;
+
+        let sync_due = match self.options.sync {
+            SyncPolicy::Always => true,
+            SyncPolicy::Interval(interval) => self.last_sync.elapsed() >= interval,
+            SyncPolicy::Never => false,
+        };
+
+        if sync_due {
+            self.sync()?;
+        }
+
+        let too_old = self
+            .options
+            .max_age
+            .is_some_and(|age| self.compacted.elapsed() >= age);
+
+        let too_large = self.size - self.compacted_size > self.options.max_file_size;
+
+        if too_large {
+            self.compact()?;
+        } else if too_old {
+            self.rewrite(usize::MAX)?;
+        }
+
+        Ok(())
+    }
+
+    /// Flushes everything appended so far to the disk.
+    pub fn sync(&mut self) -> io::Result<()> {
+        if self.dirty {
+            self.file.sync_data()?;
+
+            self.dirty = false;
+        }
+
+        self.last_sync = Instant::now();
+
+        Ok(())
+    }
+
+    /// Rewrites the log with only the newest messages that are young enough
+    /// to keep.
+    pub fn compact(&mut self) -> io::Result<()> {
+        self.rewrite(self.options.keep)
+    }
+
+    fn last_byte(path: &Path) -> io::Result<u8> {
+        let mut file = File::open(path)?;
+        let mut byte = [0];
+
+        file.seek(SeekFrom::End(-1))?;
+        file.read_exact(&mut byte)?;
+
+        Ok(byte[0])
+    }
+
+    /// Drops the messages older than the maximum age.
+    fn drop_old(messages: &mut Vec<UserMessage>, options: &StoreOptions) {
+        if let Some(age) = options.max_age {
+            let cutoff = SystemTime::now().checked_sub(age).map_or(0, unix_millis);
+
+            messages.retain(|m| m.timestamp >= cutoff);
+        }
+    }
+
+    /// Reads the messages in the log, skipping lines that cannot be parsed,
+    /// along with the id of the newest message ever written to it.
+    fn load(path: &Path) -> io::Result<(Vec<UserMessage>, u64)> {
+        let file = match File::open(path) {
+            Ok(file) => file,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok((Vec::new(), 0)),
+            Err(e) => return Err(e),
+        };
+
+        let mut messages = Vec::new();
+        let mut last_id = 0;
+
+        for (i, line) in BufReader::new(file).lines().enumerate() {
+            let line = match line {
+                Ok(line) => line,
+                // Most likely a message cut off partway through a character
+                Err(e) if e.kind() == io::ErrorKind::InvalidData => continue,
+                Err(e) => return Err(e),
+            };
+
+            let json = Json::parse(&line);
+
+            // Left by compacting, in case no message was kept
+            if let Some(id) = json.as_ref().ok().and_then(|j| j.get("last_id")?.as_u64()) {
+                last_id = last_id.max(id);
+
+                continue;
+            }
+
+            match json.and_then(|json| UserMessage::from_json(&json)) {
+                Ok(message) => {
+                    last_id = last_id.max(message.id);
+
+                    messages.push(message);
+                }
+                Err(e) => eprintln!("Skipping line {} of {}: {e}", i + 1, path.display()),
+            }
+        }
+
+        Ok((messages, last_id))
+    }
+
+    /// Writes the newest `keep` messages that are young enough to a new file
+    /// that then replaces the log, so a crash partway through leaves the old
+    /// log intact.
+    fn rewrite(&mut self, keep: usize) -> io::Result<()> {
+        let (mut messages, last_id) = Self::load(&self.path)?;
+
+        Self::drop_old(&mut messages, &self.options);
+
+        messages.drain(..messages.len().saturating_sub(keep));
+
+        let last_id = last_id.max(self.last_id);
+
+        let tmp_path = self.path.with_extension("jsonl.tmp");
+
+        let mut tmp = File::create(&tmp_path)?;
+
+        writeln!(tmp, "{}", Json::object([("last_id", last_id.into())]))?;
+
+        for message in messages.iter() {
+            writeln!(tmp, "{}", message.to_json())?;
+        }
+
+        tmp.sync_all()?;
+
+        fs::rename(&tmp_path, &self.path)?;
+
+        self.file = OpenOptions::new().append(true).open(&self.path)?;
+        self.last_id = last_id;
+        self.size = self.file.metadata()?.len();
+        self.compacted_size = self.size;
+        self.dirty = false;
+        self.last_sync = Instant::now();
+        self.compacted = Instant::now();
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::TempDir;
+
+    fn message(id: u64, timestamp: u64, text: &str) -> UserMessage {
+        UserMessage {
+            id,
+            room: "Room".to_string(),
+            timestamp,
+            username: "asd".to_string(),
+            message: text.to_string(),
+        }
+    }
+
+    fn now() -> u64 {
+        unix_millis(SystemTime::now())
+    }
+
+    #[test]
+    fn test_replay_appended_messages() {
+        let dir = TempDir::new("store");
+
+        let (mut store, messages) =
+            MessageStore::open(dir.path(), "Room", StoreOptions::default()).unwrap();
+
+        assert!(messages.is_empty());
+
+        store.append(&message(1, now(), "first")).unwrap();
+        store
+            .append(&message(2, now(), "line\nbreak \"quoted\""))
+            .unwrap();
+        drop(store);
+
+        let (_, messages) =
+            MessageStore::open(dir.path(), "Room", StoreOptions::default()).unwrap();
+
+        assert_eq!(2, messages.len());
+        assert_eq!("line\nbreak \"quoted\"", messages[1].message);
+    }
+
+    #[test]
+    fn test_rooms_have_separate_logs() {
+        let dir = TempDir::new("store");
+
+        let (mut lobby, _) =
+            MessageStore::open(dir.path(), "Lobby", StoreOptions::default()).unwrap();
+        let (games, _) =
+            MessageStore::open(dir.path(), "Game Night/2", StoreOptions::default()).unwrap();
+
+        lobby.append(&message(1, now(), "hello")).unwrap();
+
+        assert_ne!(lobby.path(), games.path());
+        assert_eq!(dir.path(), games.path().parent().unwrap());
+
+        let (_, messages) =
+            MessageStore::open(dir.path(), "Game Night/2", StoreOptions::default()).unwrap();
+
+        assert!(messages.is_empty());
+    }
+
+    #[test]
+    fn test_skips_damaged_lines() {
+        let dir = TempDir::new("store");
+
+        let (mut store, _) =
+            MessageStore::open(dir.path(), "Room", StoreOptions::default()).unwrap();
+
+        store.append(&message(1, now(), "kept")).unwrap();
+        store.file.write_all(b"{\"id\": 2, \"room\": \"Ro").unwrap();
+        drop(store);
+
+        let (mut store, messages) =
+            MessageStore::open(dir.path(), "Room", StoreOptions::default()).unwrap();
+
+        assert_eq!(
+            vec!["kept"],
+            messages
+                .iter()
+                .map(|m| m.message.as_str())
+                .collect::<Vec<_>>()
+        );
+
+        // The damaged line is gone, so it does not swallow the next message
+        store.append(&message(3, now(), "after")).unwrap();
+        drop(store);
+
+        let (_, messages) =
+            MessageStore::open(dir.path(), "Room", StoreOptions::default()).unwrap();
+
+        assert_eq!(2, messages.len());
+    }
+
+    #[test]
+    fn test_compacts_when_too_large() {
+        let dir = TempDir::new("store");
+
+        let options = StoreOptions {
+            keep: 2,
+            max_file_size: 512,
+            ..StoreOptions::default()
+        };
+
+        let (mut store, _) = MessageStore::open(dir.path(), "Room", options).unwrap();
+
+        for id in 1..=10 {
+            store
+                .append(&message(id, now(), "some words to take up space"))
+                .unwrap();
+        }
+
+        assert!(store.compacted_size < 512);
+        assert!(fs::metadata(store.path()).unwrap().len() <= store.compacted_size + 512);
+
+        drop(store);
+
+        // What was kept, and whatever came after the last compaction
+        let (_, messages) = MessageStore::open(dir.path(), "Room", options).unwrap();
+
+        assert!((2..10).contains(&messages.len()));
+        assert_eq!(Some(10), messages.last().map(|m| m.id));
+    }
+
+    #[test]
+    fn test_open_leaves_the_log_alone() {
+        let dir = TempDir::new("store");
+
+        let options = StoreOptions {
+            keep: 1,
+            ..StoreOptions::default()
+        };
+
+        let (mut store, _) = MessageStore::open(dir.path(), "Room", options).unwrap();
+
+        for id in 1..=3 {
+            store.append(&message(id, now(), "hello")).unwrap();
+        }
+
+        let size = fs::metadata(store.path()).unwrap().len();
+
+        drop(store);
+
+        for _ in 0..2 {
+            let (store, messages) = MessageStore::open(dir.path(), "Room", options).unwrap();
+
+            assert_eq!(3, messages.len());
+            assert_eq!(size, fs::metadata(store.path()).unwrap().len());
+        }
+    }
+
+    #[test]
+    fn test_ids_carry_on_when_nothing_is_kept() {
+        let dir = TempDir::new("store");
+
+        let options = StoreOptions {
+            keep: 0,
+            max_file_size: 1,
+            ..StoreOptions::default()
+        };
+
+        let (mut store, _) = MessageStore::open(dir.path(), "Room", options).unwrap();
+
+        for id in 1..=3 {
+            store.append(&message(id, now(), "hello")).unwrap();
+        }
+
+        drop(store);
+
+        let (store, messages) = MessageStore::open(dir.path(), "Room", options).unwrap();
+
+        assert!(messages.is_empty());
+        assert_eq!(3, store.last_id());
+    }
+
+    #[test]
+    fn test_kept_messages_larger_than_the_limit() {
+        let dir = TempDir::new("store");
+
+        let options = StoreOptions {
+            keep: 5,
+            max_file_size: 256,
+            ..StoreOptions::default()
+        };
+
+        let (mut store, _) = MessageStore::open(dir.path(), "Room", options).unwrap();
+
+        let mut most_lines = 0;
+
+        for id in 1..=30 {
+            store
+                .append(&message(id, now(), "some words to take up space"))
+                .unwrap();
+
+            let lines = fs::read_to_string(store.path()).unwrap().lines().count();
+
+            most_lines = most_lines.max(lines);
+        }
+
+        // The kept messages alone are past the limit, yet the log still grows
+        // between compactions instead of being rewritten on every message
+        assert!(store.compacted_size > 256);
+        assert!(most_lines > 5);
+    }
+
+    #[test]
+    fn test_drops_old_messages() {
+        let dir = TempDir::new("store");
+
+        let options = StoreOptions {
+            max_age: Some(Duration::from_secs(60 * 60)),
+            ..StoreOptions::default()
+        };
+
+        let (mut store, _) = MessageStore::open(dir.path(), "Room", options).unwrap();
+
+        store
+            .append(&message(1, now() - 2 * 60 * 60 * 1000, "stale"))
+            .unwrap();
+        store.append(&message(2, now(), "fresh")).unwrap();
+        drop(store);
+
+        let (_, messages) = MessageStore::open(dir.path(), "Room", options).unwrap();
+
+        assert_eq!(vec![2], messages.iter().map(|m| m.id).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn test_parse_sync_policy() {
+        assert_eq!(Ok(SyncPolicy::Always), "always".parse());
+        assert_eq!(Ok(SyncPolicy::Never), "never".parse());
+        assert_eq!(
+            Ok(SyncPolicy::Interval(Duration::from_secs(5))),
+            "5".parse()
+        );
+        assert_eq!(
+            Err(InvalidSyncPolicy("sometimes".to_string())),
+            "sometimes".parse::<SyncPolicy>()
+        );
+    }
+}
@@ -0,0 +1,410 @@
+use crate::error::TemplateError;
+use crate::json::Json;
+
+/// How deeply templates may include each other, which stops a template that
+/// includes itself.
+const MAX_INCLUDE_DEPTH: usize = 8;
+
+/// Escapes text so it can be placed in HTML. Most of what is shown on the
+/// pages is chosen by users, so it cannot be trusted not to contain markup.
+pub fn escape_html(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+
+    for c in text.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&#39;"),
+            c => escaped.push(c),
+        }
+    }
+
+    escaped
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Node {
+    Text(String),
+    /// A value looked up by a dotted path, e.g. `member.username`.
+    Variable(Vec<String>),
+    If {
+        condition: Vec<String>,
+        then: Vec<Node>,
+        otherwise: Vec<Node>,
+    },
+    For {
+        item: String,
+        items: Vec<String>,
+        body: Vec<Node>,
+    },
+    Include(String),
+}
+
+fn parse_path(path: &str) -> Result<Vec<String>, TemplateError> {
+    let valid = |segment: &str| {
+        !segment.is_empty()
+            && segment
+                .chars()
+                .all(|c| c.is_alphanumeric() || c == '_' || c == '-')
+    };
+
+    match path.split('.').all(valid) {
+        true => Ok(path.split('.').map(str::to_string).collect()),
+        false => Err(TemplateError::InvalidTag(path.to_string())),
+    }
+}
+
+struct Parser<'a> {
+    source: &'a str,
+    pos: usize,
+}
+
+impl<'a> Parser<'a> {
+    /// Returns the text up to the next tag, and the tag's content.
+    fn next_token(&mut self) -> Result<Option<(&'a str, Option<&'a str>)>, TemplateError> {
+        let rest = &self.source[self.pos..];
+
+        if rest.is_empty() {
+            return Ok(None);
+        }
+
+        let Some(open) = rest.find("{{") else {
+            self.pos = self.source.len();
+
+            return Ok(Some((rest, None)));
+        };
+
+        let close = rest[open..]
+            .find("}}")
+            .ok_or(TemplateError::UnclosedTag(self.pos + open))?;
+
+        self.pos += open + close + 2;
+
+        Ok(Some((
+            &rest[..open],
+            Some(rest[open + 2..open + close].trim()),
+        )))
+    }
+
+    /// Parses nodes until one of the tags that end the block, returning the
+    /// tag that did.
+    fn parse_block(
+        &mut self,
+        ends: &[&str],
+    ) -> Result<(Vec<Node>, Option<&'a str>), TemplateError> {
+        let mut nodes = Vec::new();
+
+        while let Some((text, tag)) = self.next_token()? {
+            if !text.is_empty() {
+                nodes.push(Node::Text(text.to_string()));
+            }
+
+            let Some(tag) = tag else {
+                continue;
+            };
+
+            if ends.contains(&tag) {
+                return Ok((nodes, Some(tag)));
+            }
+
+            if let Some(condition) = tag.strip_prefix("#if ") {
+                let condition = parse_path(condition.trim())?;
+
+                let (then, end) = self.parse_block(&["else", "/if"])?;
+
+                let otherwise = match end {
+                    Some("else") => self.expect_end(tag, "/if")?,
+                    Some(_) => Vec::new(),
+                    None => return Err(TemplateError::UnclosedBlock(tag.to_string())),
+                };
+
+                nodes.push(Node::If {
+                    condition,
+                    then,
+                    otherwise,
+                });
+            } else if let Some(header) = tag.strip_prefix("#for ") {
+                let parts: Vec<&str> = header.split_whitespace().collect();
+
+                let [item, "in", items] = parts[..] else {
+                    return Err(TemplateError::InvalidTag(tag.to_string()));
+                };
+
+                nodes.push(Node::For {
+                    item: item.to_string(),
+                    items: parse_path(items)?,
+                    body: self.expect_end(tag, "/for")?,
+                });
+            } else if let Some(name) = tag.strip_prefix('>') {
+                nodes.push(Node::Include(name.trim().to_string()));
+            } else if tag.starts_with('!') {
+                // A comment
+            } else if tag.starts_with(['#', '/']) || tag == "else" {
+                return Err(TemplateError::UnexpectedTag(tag.to_string()));
+            } else {
+                nodes.push(Node::Variable(parse_path(tag)?));
+            }
+        }
+
+        Ok((nodes, None))
+    }
+
+    fn expect_end(&mut self, opened: &str, end: &str) -> Result<Vec<Node>, TemplateError> {
+        match self.parse_block(&[end])? {
+            (nodes, Some(_)) => Ok(nodes),
+            (_, None) => Err(TemplateError::UnclosedBlock(opened.to_string())),
+        }
+    }
+}
+
+/// The values a template can see: the context it is rendered with, and the
+/// items of the loops it is in.
+struct Scope<'a> {
+    context: &'a Json,
+    items: Vec<(String, &'a Json)>,
+}
+
+impl<'a> Scope<'a> {
+    fn lookup(&self, path: &[String]) -> Option<&'a Json> {
+        let (first, rest) = path.split_first()?;
+
+        let mut value = match self.items.iter().rev().find(|(name, _)| name == first) {
+            Some((_, item)) => *item,
+            None => self.context.get(first)?,
+        };
+
+        for key in rest {
+            value = value.get(key)?;
+        }
+
+        Some(value)
+    }
+}
+
+fn truthy(value: Option<&Json>) -> bool {
+    match value {
+        None | Some(Json::Null) | Some(Json::Bool(false)) => false,
+        Some(Json::Number(n)) => *n != 0.0,
+        Some(Json::String(s)) => !s.is_empty(),
+        Some(Json::Array(values)) => !values.is_empty(),
+        Some(_) => true,
+    }
+}
+
+/// A page with placeholders filled in from a JSON context.
+///
+/// - `{{name}}` or `{{room.name}}` inserts a value, escaped for HTML. Missing
+///   values insert nothing.
+/// - `{{#if name}} ... {{else}} ... {{/if}}` renders the first part when the
+///   value is present and not `false`, `0`, empty or `null`.
+/// - `{{#for item in items}} ... {{/for}}` renders once for each item of an
+///   array, which is visible inside as `item`.
+/// - `{{> name}}` renders another template in place with the same values.
+/// - `{{! comment}}` renders nothing.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Template {
+    nodes: Vec<Node>,
+}
+
+impl Template {
+    pub fn parse(source: &str) -> Result<Self, TemplateError> {
+        let mut parser = Parser { source, pos: 0 };
+
+        let (nodes, _) = parser.parse_block(&[])?;
+
+        Ok(Self { nodes })
+    }
+
+    /// Renders the template with the context, loading the source of included
+    /// templates by name with `load`.
+    pub fn render(
+        &self,
+        context: &Json,
+        load: &dyn Fn(&str) -> Option<String>,
+    ) -> Result<String, TemplateError> {
+        let mut output = String::new();
+
+        let mut scope = Scope {
+            context,
+            items: Vec::new(),
+        };
+
+        render_nodes(&self.nodes, &mut scope, load, 0, &mut output)?;
+
+        Ok(output)
+    }
+}
+
+fn render_nodes<'a>(
+    nodes: &[Node],
+    scope: &mut Scope<'a>,
+    load: &dyn Fn(&str) -> Option<String>,
+    depth: usize,
+    output: &mut String,
+) -> Result<(), TemplateError> {
+    for node in nodes {
+        match node {
+            Node::Text(text) => output.push_str(text),
+            Node::Variable(path) => match scope.lookup(path) {
+                None | Some(Json::Null) => (),
+                Some(Json::String(s)) => output.push_str(&escape_html(s)),
+                Some(value) => output.push_str(&escape_html(&value.to_string())),
+            },
+            Node::If {
+                condition,
+                then,
+                otherwise,
+            } => {
+                let branch = match truthy(scope.lookup(condition)) {
+                    true => then,
+                    false => otherwise,
+                };
+
+                render_nodes(branch, scope, load, depth, output)?;
+            }
+            Node::For { item, items, body } => {
+                let values = scope.lookup(items).and_then(Json::as_array).unwrap_or(&[]);
+
+                for value in values {
+                    scope.items.push((item.clone(), value));
+
+                    let rendered = render_nodes(body, scope, load, depth, output);
+
+                    scope.items.pop();
+
+                    rendered?;
+                }
+            }
+            Node::Include(name) => {
+                if depth >= MAX_INCLUDE_DEPTH {
+                    return Err(TemplateError::IncludeTooDeep);
+                }
+
+                let source =
+                    load(name).ok_or_else(|| TemplateError::MissingInclude(name.clone()))?;
+
+                let included = Template::parse(&source)?;
+
+                render_nodes(&included.nodes, scope, load, depth + 1, output)?;
+            }
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn render(source: &str, context: &Json) -> Result<String, TemplateError> {
+        Template::parse(source)?.render(context, &|name| match name {
+            "greeting" => Some("Hi {{name}}!".to_string()),
+            "itself" => Some("{{> itself}}".to_string()),
+            _ => None,
+        })
+    }
+
+    #[test]
+    fn test_variables_are_escaped() {
+        let context = Json::object([
+            ("name", "<b>asd</b> & \"co\"".into()),
+            ("room", Json::object([("members", 3u64.into())])),
+        ]);
+
+        assert_eq!(
+            Ok("Hello &lt;b&gt;asd&lt;/b&gt; &amp; &quot;co&quot;, 3 here".to_string()),
+            render("Hello {{ name }}, {{room.members}} here", &context)
+        );
+        assert_eq!(
+            Ok("[]".to_string()),
+            render("[{{missing.field}}]", &context)
+        );
+    }
+
+    #[test]
+    fn test_conditionals() {
+        let template = "{{#if error}}<p>{{error}}</p>{{else}}All good{{/if}}";
+
+        assert_eq!(
+            Ok("<p>Wrong code</p>".to_string()),
+            render(template, &Json::object([("error", "Wrong code".into())]))
+        );
+        assert_eq!(
+            Ok("All good".to_string()),
+            render(template, &Json::object([("error", "".into())]))
+        );
+        assert_eq!(
+            Ok("".to_string()),
+            render(
+                "{{#if items}}some{{/if}}",
+                &Json::object([("items", Json::Array(vec![]))])
+            )
+        );
+    }
+
+    #[test]
+    fn test_loops() {
+        let context = Json::object([
+            ("room", "Games".into()),
+            (
+                "members",
+                Json::Array(vec![
+                    Json::object([("name", "asd".into()), ("online", true.into())]),
+                    Json::object([("name", "qwe".into()), ("online", false.into())]),
+                ]),
+            ),
+        ]);
+
+        let template = "{{#for member in members}}<li>{{member.name}}{{#if member.online}}*{{/if}} in {{room}}</li>{{/for}}";
+
+        assert_eq!(
+            Ok("<li>asd* in Games</li><li>qwe in Games</li>".to_string()),
+            render(template, &context)
+        );
+    }
+
+    #[test]
+    fn test_includes() {
+        let context = Json::object([("name", "asd".into())]);
+
+        assert_eq!(
+            Ok("<h1>Hi asd!</h1>".to_string()),
+            render("<h1>{{> greeting}}</h1>{{! not shown }}", &context)
+        );
+        assert_eq!(
+            Err(TemplateError::MissingInclude("nope".to_string())),
+            render("{{> nope}}", &context)
+        );
+        assert_eq!(
+            Err(TemplateError::IncludeTooDeep),
+            render("{{> itself}}", &context)
+        );
+    }
+
+    #[test]
+    fn test_invalid_templates() {
+        assert_eq!(
+            Err(TemplateError::UnclosedTag(6)),
+            Template::parse("Hello {{name")
+        );
+        assert_eq!(
+            Err(TemplateError::UnclosedBlock("#if name".to_string())),
+            Template::parse("{{#if name}}no end")
+        );
+        assert_eq!(
+            Err(TemplateError::UnexpectedTag("/for".to_string())),
+            Template::parse("{{#if name}}{{/for}}")
+        );
+        assert_eq!(
+            Err(TemplateError::InvalidTag("#for members".to_string())),
+            Template::parse("{{#for members}}{{/for}}")
+        );
+        assert_eq!(
+            Err(TemplateError::InvalidTag("".to_string())),
+            Template::parse("{{}}")
+        );
+    }
+}